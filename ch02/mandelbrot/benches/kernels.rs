@@ -0,0 +1,68 @@
+//! Time the scalar escape-time loop and the kernel on a few views. Run with
+//! `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use num::Complex;
+
+// The renderer is a binary with no library to link against, so compile the
+// kernel's source in here too.
+#[allow(dead_code)]
+#[path = "../src/simd.rs"]
+mod simd;
+
+const BOUNDS: (usize, usize) = (400, 300);
+const LIMIT: usize = 1000;
+
+/// The loop `Mandelbrot::escape_time` runs, one point at a time.
+fn scalar_escape_time(c: Complex<f64>, limit: usize) -> Option<usize> {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    for i in 0..limit {
+        if z.norm_sqr() > 4.0 {
+            return Some(i);
+        }
+        z = z * z + c;
+    }
+    None
+}
+
+fn kernels(criterion: &mut Criterion) {
+    let views = [
+        // Half the points deep inside, in the cardioid and bulb.
+        ("whole set", Complex { re: -0.5, im: 0.0 }, 3.5),
+        // Mostly boundary, with inside points in small bulbs the shortcuts
+        // don't cover, left to periodicity checking.
+        (
+            "seahorse valley",
+            Complex {
+                re: -0.745,
+                im: 0.11,
+            },
+            0.05,
+        ),
+        // Just right of the cusp, where everything escapes, but slowly: no
+        // shortcuts apply, so this is the arithmetic alone.
+        ("outside", Complex { re: 0.3, im: 0.0 }, 0.04),
+    ];
+
+    for (name, center, width) in views {
+        let points = simd::grid(center, width, BOUNDS);
+        let mut counts = vec![None; points.len()];
+        let mut group = criterion.benchmark_group(name);
+        group.sample_size(10);
+
+        group.bench_function("scalar", |bencher| {
+            bencher.iter(|| {
+                for (point, count) in points.iter().zip(&mut counts) {
+                    *count = scalar_escape_time(*point, LIMIT).map(|count| count as f64);
+                }
+            })
+        });
+        group.bench_function("kernel", |bencher| {
+            bencher.iter(|| simd::escape_times(&points, LIMIT, false, &mut counts))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, kernels);
+criterion_main!(benches);
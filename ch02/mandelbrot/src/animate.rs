@@ -0,0 +1,139 @@
+//! Zoom animations, rendered as a numbered image per frame.
+//!
+//! Frames are written under a temporary name and renamed once complete, so a
+//! frame file that exists is a finished frame. Running the same animation
+//! again after an interruption skips them and carries on from the first one
+//! missing.
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use num::Complex;
+
+/// How the animation speeds up and slows down between its ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Map the fraction of frames done, from 0.0 to 1.0, to the fraction of
+    /// the journey made.
+    pub fn apply(self, t: f64) -> f64 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+impl FromStr for Easing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Easing, String> {
+        match s {
+            "linear" => Ok(Easing::Linear),
+            "ease-in" => Ok(Easing::EaseIn),
+            "ease-out" => Ok(Easing::EaseOut),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            _ => Err(format!(
+                "unknown easing {s:?} (expected linear, ease-in, ease-out or ease-in-out)"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Animation {
+    pub frames: usize,
+    pub start_center: Complex<f64>,
+    pub start_zoom: f64,
+    pub end_center: Complex<f64>,
+    pub end_zoom: f64,
+    pub easing: Easing,
+}
+
+impl Animation {
+    /// The center and zoom of frame `frame`, counting from zero.
+    ///
+    /// The zoom changes by the same factor from frame to frame, which looks
+    /// like steady motion. The center moves in step with the view's width, so
+    /// the end center drifts across the screen steadily too, rather than
+    /// arriving in a rush once the view has grown tiny.
+    pub fn view(&self, frame: usize) -> (Complex<f64>, f64) {
+        let t = if self.frames > 1 {
+            frame as f64 / (self.frames - 1) as f64
+        } else {
+            1.0
+        };
+        let t = self.easing.apply(t);
+
+        let zoom = self.start_zoom * (self.end_zoom / self.start_zoom).powf(t);
+        // How far the view's width has shrunk, as a fraction of the whole
+        // shrinkage from start to end.
+        let travelled = if self.end_zoom == self.start_zoom {
+            t
+        } else {
+            (1.0 - self.start_zoom / zoom) / (1.0 - self.start_zoom / self.end_zoom)
+        };
+        let center = self.start_center + (self.end_center - self.start_center) * travelled;
+        (center, zoom)
+    }
+
+    /// Where frame `frame` goes in `directory`, with file extension
+    /// `extension`.
+    pub fn frame_path(&self, directory: &Path, frame: usize, extension: &str) -> PathBuf {
+        let digits = self.frames.to_string().len().max(5);
+        directory.join(format!("frame-{frame:0digits$}.{extension}"))
+    }
+}
+
+#[test]
+fn test_easing() {
+    for easing in [
+        Easing::Linear,
+        Easing::EaseIn,
+        Easing::EaseOut,
+        Easing::EaseInOut,
+    ] {
+        assert_eq!(easing.apply(0.0), 0.0);
+        assert_eq!(easing.apply(1.0), 1.0);
+    }
+    assert!(Easing::EaseIn.apply(0.5) < 0.5);
+    assert!(Easing::EaseOut.apply(0.5) > 0.5);
+    assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+    assert_eq!("ease-in-out".parse(), Ok(Easing::EaseInOut));
+    assert!("bouncy".parse::<Easing>().is_err());
+}
+
+#[test]
+fn test_view() {
+    let animation = Animation {
+        frames: 5,
+        start_center: Complex { re: -0.5, im: 0.0 },
+        start_zoom: 1.0,
+        end_center: Complex { re: -0.75, im: 0.1 },
+        end_zoom: 10000.0,
+        easing: Easing::Linear,
+    };
+    assert_eq!(animation.view(0), (animation.start_center, 1.0));
+    let (center, zoom) = animation.view(4);
+    assert!((center - animation.end_center).norm() < 1e-12);
+    assert!((zoom - 10000.0).abs() < 1e-6);
+
+    // Each frame zooms in ten times on the last.
+    let (_, zoom) = animation.view(2);
+    assert!((zoom - 100.0).abs() < 1e-9);
+
+    assert_eq!(
+        animation.frame_path(Path::new("out"), 3, "png"),
+        Path::new("out/frame-00003.png")
+    );
+}
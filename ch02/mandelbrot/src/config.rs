@@ -0,0 +1,451 @@
+//! Render settings, taken from the command line.
+
+use std::{str::FromStr, thread};
+
+use num::Complex;
+
+use crate::{
+    animate::{Animation, Easing},
+    antialias::Sampling,
+    deep::DeepView,
+    fractal::{parse_fractal, Fractal, Mandelbrot},
+    palette::{Gradient, Mapping, Palette},
+    parse_complex, parse_pair,
+};
+
+pub const USAGE: &str = "Usage: mandelbrot FILE PIXELS [UPPERLEFT LOWERRIGHT] \
+    [--fractal NAME] [--center X,Y] [--zoom Z] [--max-iter N] [--threads N] \
+    [--format png|jpeg|ppm|tiff] [--palette NAME|STOPS] [--colouring MODE] [--samples MODE] [--stats] [--deep]
+       mandelbrot animate DIRECTORY PIXELS --frames N --end-center X,Y --end-zoom Z \
+    [--easing NAME] [--center X,Y] [--zoom Z] [OPTIONS]
+Example: mandelbrot mandel.png 1000x750 -1.20,0.35 -1,0.20
+         mandelbrot mandel.jpg 1000x750 --center -0.75,0.1 --zoom 40 --palette fire
+         mandelbrot animate frames 640x480 --frames 300 --end-center -0.7453,0.1127 \
+    --end-zoom 1e6 --easing ease-in-out
+
+--fractal is mandelbrot (the default), julia:RE,IM, burning-ship, tricorn or
+multibrot:POWER.
+The view is either the UPPERLEFT and LOWERRIGHT corner points, or --center and
+--zoom, where zoom 1 is four units wide. With neither, the whole set is drawn.
+--format defaults to FILE's extension, or png.
+--palette is grey, fire, ocean or ultra (the default), or colour stops from 0
+to 1, like 0:000764,0.5:ffaa00,1:000764.
+--colouring is linear, cyclic[:PERIOD], histogram (the default), or banded for
+whole counts mapped linearly, as the book does.
+--samples smooths edges by colouring several points per pixel: N for an N by N
+grid, jittered:N for a grid with each point moved at random within its cell,
+or adaptive:N for a grid only where neighbouring pixels' colours differ.
+--deep zooms into the Mandelbrot set beyond what 64-bit floats can show, up to
+about 1e300, with --center given to as many digits as the zoom needs.
+--stats reports how many rows each thread rendered, and how long it took.
+animate writes numbered frames into DIRECTORY, zooming from --center and
+--zoom to --end-center and --end-zoom. --easing is linear (the default),
+ease-in, ease-out or ease-in-out. Run it again with the same options to carry
+on after an interruption.";
+
+/// The file formats we can write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Ppm,
+    Tiff,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Ppm => "ppm",
+            Format::Tiff => "tiff",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(Format::Png),
+            "jpeg" | "jpg" => Ok(Format::Jpeg),
+            "ppm" => Ok(Format::Ppm),
+            "tiff" | "tif" => Ok(Format::Tiff),
+            _ => Err(format!("unknown image format {s:?}")),
+        }
+    }
+}
+
+pub struct Config {
+    pub output: String,
+    pub fractal: Box<dyn Fractal>,
+    pub bounds: (usize, usize),
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>,
+    /// How many iterations to try before deciding a point is in the set.
+    pub max_iter: usize,
+    pub threads: usize,
+    /// Whether to report each thread's share of the work when done.
+    pub stats: bool,
+    pub format: Format,
+    pub palette: Palette,
+    /// Whether to use fractional escape counts, for colouring without bands.
+    pub smooth: bool,
+    pub sampling: Sampling,
+    /// Set for deep zooms, which render by perturbation instead of through
+    /// `fractal`.
+    pub deep: Option<DeepView>,
+    /// Set when rendering an animation, in which case `output` is the
+    /// directory for its frames, and the corner points are its first frame's.
+    pub animation: Option<Animation>,
+}
+
+impl Config {
+    /// Parse the program's arguments, not including the program name.
+    pub fn from_args<I>(args: I) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let mut positional = vec![];
+        let mut fractal: Box<dyn Fractal> = Box::new(Mandelbrot);
+        let mut fractal_name = "mandelbrot".to_string();
+        let mut center = None;
+        // The center as given, with more digits than `center` can keep.
+        let mut center_text = None;
+        let mut zoom = None;
+        let mut format = None;
+        let mut gradient = Gradient::named("ultra").unwrap();
+        let mut colouring = "histogram".to_string();
+        let mut max_iter = 255;
+        let mut threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut stats = false;
+        let mut deep = false;
+        let mut frames = None;
+        let mut end_center = None;
+        let mut end_zoom = None;
+        let mut easing = Easing::Linear;
+        let mut sampling = Sampling::Single;
+
+        while let Some(arg) = args.next() {
+            // Negative numbers, like the corner `-1.20,0.35`, aren't options.
+            if !arg.starts_with("--") {
+                positional.push(arg);
+                continue;
+            }
+            if arg == "--stats" || arg == "--deep" {
+                stats |= arg == "--stats";
+                deep |= arg == "--deep";
+                continue;
+            }
+
+            let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+            let bad_value = || format!("bad value for {arg}: {value:?}");
+            match arg.as_str() {
+                "--fractal" => {
+                    fractal = parse_fractal(&value)?;
+                    fractal_name = value;
+                }
+                "--center" => {
+                    center = Some(parse_complex(&value).ok_or_else(bad_value)?);
+                    center_text = Some(value);
+                }
+                "--zoom" => zoom = Some(parse_zoom(&value).ok_or_else(bad_value)?),
+                "--frames" => frames = Some(parse_positive(&value).ok_or_else(bad_value)?),
+                "--end-center" => end_center = Some(parse_complex(&value).ok_or_else(bad_value)?),
+                "--end-zoom" => end_zoom = Some(parse_zoom(&value).ok_or_else(bad_value)?),
+                "--easing" => easing = value.parse()?,
+                "--max-iter" => max_iter = parse_positive(&value).ok_or_else(bad_value)?,
+                "--threads" => threads = parse_positive(&value).ok_or_else(bad_value)?,
+                "--format" => format = Some(value.parse()?),
+                "--palette" => gradient = value.parse()?,
+                "--colouring" => colouring = value,
+                "--samples" => sampling = value.parse()?,
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+
+        let animating = positional.first().is_some_and(|first| first == "animate");
+        let mut positional = positional.into_iter().skip(animating as usize);
+        let output = positional.next().ok_or("missing FILE")?;
+        let pixels = positional.next().ok_or("missing PIXELS")?;
+        let bounds: (usize, usize) = parse_pair(&pixels, 'x')
+            .filter(|&(width, height)| width > 0 && height > 0)
+            .ok_or_else(|| format!("bad image size {pixels:?}, expected WIDTHxHEIGHT"))?;
+
+        let corner_points = (positional.next(), positional.next());
+        let animation = if animating {
+            if corner_points.0.is_some() {
+                return Err(
+                    "animate starts from --center and --zoom, not corner points".to_string()
+                );
+            }
+            if deep {
+                return Err("animate can't do --deep zooms".to_string());
+            }
+            Some(Animation {
+                frames: frames.ok_or("animate needs --frames")?,
+                start_center: center.unwrap_or_else(|| fractal.home()),
+                start_zoom: zoom.unwrap_or(1.0),
+                end_center: end_center.ok_or("animate needs --end-center")?,
+                end_zoom: end_zoom.ok_or("animate needs --end-zoom")?,
+                easing,
+            })
+        } else {
+            if frames.is_some() || end_center.is_some() || end_zoom.is_some() {
+                return Err("--frames, --end-center and --end-zoom are for animate".to_string());
+            }
+            None
+        };
+        let deep = if deep {
+            if fractal_name != "mandelbrot" {
+                return Err("--deep only works for the Mandelbrot set".to_string());
+            }
+            if corner_points.0.is_some() {
+                return Err("--deep needs --center and --zoom, not corner points".to_string());
+            }
+            let given = center_text.as_deref().unwrap_or("-0.5,0");
+            let view = DeepView::new(given, zoom.unwrap_or(1.0), bounds)
+                .ok_or_else(|| format!("bad --center for --deep {given:?}, or --zoom too deep"))?;
+            // The corner points below, rounded to `f64`, still describe the
+            // view, if only roughly.
+            center = Some(view.center());
+            Some(view)
+        } else {
+            None
+        };
+
+        let (upper_left, lower_right) = match corner_points {
+            (Some(upper_left), Some(lower_right)) => {
+                if center.is_some() || zoom.is_some() {
+                    return Err(
+                        "give either corner points or --center and --zoom, not both".to_string()
+                    );
+                }
+                let upper_left = parse_complex(&upper_left)
+                    .ok_or_else(|| format!("bad upper left corner point {upper_left:?}"))?;
+                let lower_right = parse_complex(&lower_right)
+                    .ok_or_else(|| format!("bad lower right corner point {lower_right:?}"))?;
+                if upper_left.re >= lower_right.re || upper_left.im <= lower_right.im {
+                    return Err("UPPERLEFT must be above and to the left of LOWERRIGHT".to_string());
+                }
+                (upper_left, lower_right)
+            }
+            (Some(_), None) => return Err("missing LOWERRIGHT".into()),
+            _ => corners(
+                center.unwrap_or_else(|| fractal.home()),
+                zoom.unwrap_or(1.0),
+                bounds,
+            ),
+        };
+        if let Some(extra) = positional.next() {
+            return Err(format!("unexpected argument {extra:?}"));
+        }
+
+        let format = match format {
+            Some(format) => format,
+            None if animating => Format::Png,
+            None => output
+                .rsplit_once('.')
+                .and_then(|(_, extension)| extension.parse().ok())
+                .unwrap_or(Format::Png),
+        };
+
+        let (mapping, smooth) = match colouring.as_str() {
+            "banded" => (Mapping::Linear, false),
+            colouring => (colouring.parse()?, true),
+        };
+
+        Ok(Config {
+            output,
+            fractal,
+            bounds,
+            upper_left,
+            lower_right,
+            max_iter,
+            threads,
+            stats,
+            format,
+            palette: Palette {
+                gradient,
+                mapping,
+                inside: [0, 0, 0],
+            },
+            smooth,
+            sampling,
+            deep,
+            animation,
+        })
+    }
+}
+
+/// The corners of a view centred on `center`, four units wide at zoom 1,
+/// with the same shape as an image of `bounds` pixels.
+pub fn corners(
+    center: Complex<f64>,
+    zoom: f64,
+    bounds: (usize, usize),
+) -> (Complex<f64>, Complex<f64>) {
+    let width = 4.0 / zoom;
+    let height = width * bounds.1 as f64 / bounds.0 as f64;
+    let half = Complex {
+        re: width / 2.0,
+        im: height / 2.0,
+    };
+    (
+        Complex {
+            re: center.re - half.re,
+            im: center.im + half.im,
+        },
+        Complex {
+            re: center.re + half.re,
+            im: center.im - half.im,
+        },
+    )
+}
+
+fn parse_zoom(s: &str) -> Option<f64> {
+    parse_positive::<f64>(s).filter(|zoom| zoom.is_finite())
+}
+
+fn parse_positive<T: FromStr + PartialOrd + Default>(s: &str) -> Option<T> {
+    s.parse::<T>().ok().filter(|n| *n > T::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = parse(&[
+            "mandel.png",
+            "1000x750",
+            "-1.20,0.35",
+            "-1,0.20",
+            "--max-iter",
+            "1000",
+            "--threads",
+            "3",
+            "--stats",
+            "--samples",
+            "adaptive:3",
+        ])
+        .unwrap();
+        assert_eq!(config.bounds, (1000, 750));
+        assert_eq!(config.upper_left, Complex { re: -1.2, im: 0.35 });
+        assert_eq!(config.lower_right, Complex { re: -1.0, im: 0.2 });
+        assert_eq!(config.max_iter, 1000);
+        assert_eq!(config.threads, 3);
+        assert!(config.stats);
+        assert_eq!(config.sampling, Sampling::Adaptive(3));
+        assert_eq!(config.format, Format::Png);
+        assert!(config.smooth);
+
+        let config = parse(&[
+            "zoomed.JPG",
+            "400x200",
+            "--center",
+            "-0.5,1",
+            "--zoom",
+            "2",
+            "--colouring",
+            "banded",
+        ])
+        .unwrap();
+        assert_eq!(config.upper_left, Complex { re: -1.5, im: 1.5 });
+        assert_eq!(config.lower_right, Complex { re: 0.5, im: 0.5 });
+        assert_eq!(config.format, Format::Jpeg);
+        assert!(!config.smooth);
+
+        let config = parse(&[
+            "deep.png",
+            "100x100",
+            "--deep",
+            "--center",
+            "-1.74995768370609350360221450607069970727,0.000000000000000000000000000001",
+            "--zoom",
+            "1e30",
+        ])
+        .unwrap();
+        assert!(config.deep.is_some());
+        assert_eq!(config.upper_left.re, -1.7499576837060935);
+
+        let config = parse(&[
+            "animate",
+            "frames",
+            "64x48",
+            "--frames",
+            "10",
+            "--end-center",
+            "-0.75,0.1",
+            "--end-zoom",
+            "1e6",
+            "--easing",
+            "ease-in",
+        ])
+        .unwrap();
+        assert_eq!(config.output, "frames");
+        assert_eq!(config.format, Format::Png);
+        let animation = config.animation.unwrap();
+        assert_eq!(animation.frames, 10);
+        assert_eq!(animation.start_center, Complex { re: -0.5, im: 0.0 });
+        assert_eq!(animation.end_zoom, 1e6);
+        assert_eq!(animation.easing, Easing::EaseIn);
+
+        let config = parse(&["out.png", "10x10", "--palette", "0:ffffff,1:000000"]).unwrap();
+        assert_eq!(config.palette.gradient, Gradient::named("grey").unwrap());
+
+        let config = parse(&["out.img", "10x10", "--format", "tiff"]).unwrap();
+        assert_eq!(config.format, Format::Tiff);
+        assert_eq!(config.upper_left, Complex { re: -2.5, im: 2.0 });
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["mandel.png"]).is_err());
+        assert!(parse(&["mandel.png", "0x750"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "-1.20,0.35"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "-1,0.20", "-1.20,0.35"]).is_err());
+        assert!(parse(&[
+            "mandel.png",
+            "1000x750",
+            "-1.20,0.35",
+            "-1,0.20",
+            "--zoom",
+            "2"
+        ])
+        .is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--zoom", "0"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--threads", "0"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--max-iter"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--format", "gif"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--palette", "plaid"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--palette", "1:ffffff,0:000000"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--colouring", "zigzag"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--bogus", "1"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--samples", "lots"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--frames", "10"]).is_err());
+        assert!(parse(&["animate", "frames", "64x48", "--frames", "10"]).is_err());
+        assert!(parse(&[
+            "animate",
+            "frames",
+            "64x48",
+            "-1,1",
+            "1,-1",
+            "--frames",
+            "10",
+            "--end-center",
+            "0,0",
+            "--end-zoom",
+            "2"
+        ])
+        .is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--deep", "--fractal", "tricorn"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "-1,1", "1,-1", "--deep"]).is_err());
+        assert!(parse(&["mandel.png", "1000x750", "--deep", "--zoom", "1e400"]).is_err());
+    }
+}
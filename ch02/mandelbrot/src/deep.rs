@@ -0,0 +1,258 @@
+//! Zooming into the Mandelbrot set past where `f64` gives out.
+//!
+//! Around a zoom of 1e13, neighbouring pixels' points differ by less than an
+//! `f64` can tell apart near the set, and the image breaks up into blocks.
+//! Rather than iterate every pixel with big numbers, we iterate just the
+//! view's center that way, as a reference orbit `Z`, and then each pixel's
+//! small offset `dz` from that orbit in `f64`:
+//!
+//! ```text
+//! dz' = (2Z + dz) dz + dc
+//! ```
+//!
+//! where `dc` is the pixel's offset from the center. The offsets are tiny but
+//! `f64` has exponent to spare, which is where the precision was lacking. When
+//! a pixel's orbit comes closer to zero than to the reference orbit, its
+//! offset would lose precision, so we start it over against the reference
+//! orbit's beginning; this also copes with reference orbits that escape early.
+//!
+//! The offsets still have to fit in an `f64`, so zooms stop around 1e300.
+
+use num::{BigInt, Complex, One, Signed, ToPrimitive, Zero};
+
+/// Escaping past a larger radius than two makes smooth counts smoother, as
+/// in `Fractal::smooth_escape_time`.
+const RADIUS_SQR: f64 = 256.0 * 256.0;
+
+/// A fixed-point complex number, `re` and `im` scaled by 2^bits.
+#[derive(Clone, Debug, PartialEq)]
+struct Fixed {
+    re: BigInt,
+    im: BigInt,
+}
+
+/// A view of the Mandelbrot set deep enough to need perturbation.
+#[derive(Clone, Debug)]
+pub struct DeepView {
+    center: Fixed,
+    /// The number of fractional bits in `center` and the reference orbit.
+    bits: usize,
+    /// The distance between neighbouring pixels' points.
+    spacing: f64,
+    bounds: (usize, usize),
+}
+
+impl DeepView {
+    /// A view of `bounds` pixels centred on `center`, written `RE,IM` in
+    /// decimal to as many digits as the zoom needs, four units wide at zoom 1.
+    pub fn new(center: &str, zoom: f64, bounds: (usize, usize)) -> Option<DeepView> {
+        let spacing = 4.0 / zoom / bounds.0 as f64;
+        if !(spacing.is_normal() && spacing > 0.0) {
+            return None;
+        }
+        // Enough bits to tell pixels apart, and plenty more for the reference
+        // orbit's errors to grow into before they reach `f64`'s precision.
+        let bits = (-spacing.log2()).max(0.0).ceil() as usize + 64;
+
+        let (re, im) = center.split_once(',')?;
+        Some(DeepView {
+            center: Fixed {
+                re: parse_decimal(re, bits)?,
+                im: parse_decimal(im, bits)?,
+            },
+            bits,
+            spacing,
+            bounds,
+        })
+    }
+
+    /// The center, rounded to the nearest `f64`.
+    pub fn center(&self) -> Complex<f64> {
+        Complex {
+            re: to_f64(&self.center.re, self.bits),
+            im: to_f64(&self.center.im, self.bits),
+        }
+    }
+
+    /// The offset from the center of the point at `pixel`, which needn't be
+    /// whole pixels.
+    pub fn offset(&self, pixel: (f64, f64)) -> Complex<f64> {
+        Complex {
+            re: (pixel.0 - self.bounds.0 as f64 / 2.0) * self.spacing,
+            im: (self.bounds.1 as f64 / 2.0 - pixel.1) * self.spacing,
+        }
+    }
+
+    /// Iterate the center at full precision for up to `limit` steps, or until
+    /// it escapes. The orbit is returned rounded to `f64`, since its values
+    /// are ordinary sizes; it's where they came from that needed the bits.
+    pub fn reference_orbit(&self, limit: usize) -> Vec<Complex<f64>> {
+        let bits = self.bits;
+        let c = &self.center;
+        let mut z = Fixed {
+            re: BigInt::zero(),
+            im: BigInt::zero(),
+        };
+        let mut orbit = Vec::with_capacity(limit + 1);
+        loop {
+            let rounded = Complex {
+                re: to_f64(&z.re, bits),
+                im: to_f64(&z.im, bits),
+            };
+            orbit.push(rounded);
+            // Keep the escaping value too: `escape_time` always looks one
+            // step ahead of where it is.
+            if orbit.len() > limit || rounded.norm_sqr() > RADIUS_SQR {
+                return orbit;
+            }
+
+            let re_sqr = &z.re * &z.re;
+            let im_sqr = &z.im * &z.im;
+            let re_im = &z.re * &z.im;
+            z = Fixed {
+                re: ((re_sqr - im_sqr) >> bits) + &c.re,
+                im: ((re_im << 1) >> bits) + &c.im,
+            };
+        }
+    }
+}
+
+/// The escape count for the point `offset` away from the center of the
+/// reference orbit `orbit`, as `Fractal::escape_time` or, when `smooth` is
+/// true, `Fractal::smooth_escape_time` would give for the Mandelbrot set.
+pub fn escape_time(
+    orbit: &[Complex<f64>],
+    offset: Complex<f64>,
+    limit: usize,
+    smooth: bool,
+) -> Option<f64> {
+    let radius_sqr = if smooth { RADIUS_SQR } else { 4.0 };
+    let mut dz = Complex { re: 0.0, im: 0.0 };
+    // Where we are along the reference orbit, which isn't always `i`.
+    let mut m = 0;
+    for i in 0..limit {
+        let z = orbit[m] + dz;
+        let norm_sqr = z.norm_sqr();
+        if norm_sqr > radius_sqr {
+            if !smooth {
+                return Some(i as f64);
+            }
+            let log_modulus = norm_sqr.ln() / 2.0;
+            return Some((i as f64 + 1.0 - log_modulus.log2()).max(0.0));
+        }
+
+        // Start over from the reference orbit's zero, where `dz` is `z`.
+        if norm_sqr < dz.norm_sqr() || m == orbit.len() - 1 {
+            dz = z;
+            m = 0;
+        }
+        dz = (orbit[m] * 2.0 + dz) * dz + offset;
+        m += 1;
+    }
+    None
+}
+
+/// Parse a decimal number like `-0.7436438870371587047` exactly, then round
+/// it to a fixed-point number with `bits` fractional bits.
+fn parse_decimal(s: &str, bits: usize) -> Option<BigInt> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    if !whole
+        .chars()
+        .chain(fraction.chars())
+        .all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    // The number is `mantissa / 10^fraction.len()`.
+    let mantissa: BigInt = format!("0{whole}{fraction}").parse().ok()?;
+    let denominator = num::pow(BigInt::from(10), fraction.len());
+    let scaled = (mantissa << bits) + (&denominator >> 1_usize);
+    let value = scaled / denominator;
+    Some(if negative { -value } else { value })
+}
+
+/// Convert a fixed-point number with `bits` fractional bits to the nearest
+/// `f64`, or near enough. `BigInt::to_f64` alone would overflow for the
+/// thousands of bits a deep zoom uses, before we could scale it back down.
+fn to_f64(x: &BigInt, bits: usize) -> f64 {
+    const KEEP: usize = 64;
+    let (kept, shift) = if bits > KEEP {
+        (x >> (bits - KEEP), KEEP)
+    } else {
+        (x.clone(), bits)
+    };
+    kept.to_f64()
+        .unwrap_or(if x.is_negative() { f64::MIN } else { f64::MAX })
+        / (BigInt::one() << shift).to_f64().unwrap()
+}
+
+#[test]
+fn test_parse_decimal() {
+    assert_eq!(parse_decimal("1", 4), Some(BigInt::from(16)));
+    assert_eq!(parse_decimal("-0.5", 4), Some(BigInt::from(-8)));
+    assert_eq!(parse_decimal(".25", 4), Some(BigInt::from(4)));
+    // 0.1 * 16 = 1.6, which rounds to 2.
+    assert_eq!(parse_decimal("0.1", 4), Some(BigInt::from(2)));
+    assert_eq!(parse_decimal("", 4), None);
+    assert_eq!(parse_decimal("-", 4), None);
+    assert_eq!(parse_decimal("1e5", 4), None);
+    assert_eq!(parse_decimal("1.2.3", 4), None);
+
+    let third = parse_decimal("0.3333333333333333333333333333333333", 100).unwrap();
+    assert!((to_f64(&third, 100) - 1.0 / 3.0).abs() < 1e-16);
+}
+
+#[test]
+fn test_matches_direct_iteration() {
+    use crate::fractal::{Fractal, Mandelbrot};
+
+    // Shallow enough for `f64` alone, so both ways should mostly agree.
+    let bounds = (60, 40);
+    let view = DeepView::new("-0.7453,0.1127", 2000.0, bounds).unwrap();
+    let orbit = view.reference_orbit(500);
+    let mut agree = 0;
+    for row in 0..bounds.1 {
+        for column in 0..bounds.0 {
+            let offset = view.offset((column as f64, row as f64));
+            let perturbed = escape_time(&orbit, offset, 500, false);
+            let direct = Mandelbrot.escape_time(view.center() + offset, 500);
+            if perturbed == direct.map(|count| count as f64) {
+                agree += 1;
+            }
+        }
+    }
+    assert!(agree * 100 >= bounds.0 * bounds.1 * 99);
+}
+
+#[test]
+fn test_deeper_than_f64() {
+    use crate::fractal::{Fractal, Mandelbrot};
+
+    let bounds = (64, 1);
+    // `i` is on the boundary, so there is detail around it at any depth.
+    let view = DeepView::new("0,1", 1e25, bounds).unwrap();
+    let orbit = view.reference_orbit(5000);
+    let deep: Vec<_> = (0..bounds.0)
+        .map(|column| escape_time(&orbit, view.offset((column as f64, 0.0)), 5000, false))
+        .collect();
+    let shallow: Vec<_> = (0..bounds.0)
+        .map(|column| {
+            Mandelbrot.escape_time(view.center() + view.offset((column as f64, 0.0)), 5000)
+        })
+        .collect();
+
+    // At this zoom, `f64` can't tell the pixels apart at all.
+    fn changes<T: PartialEq>(counts: &[T]) -> usize {
+        counts.windows(2).filter(|pair| pair[0] != pair[1]).count()
+    }
+    assert_eq!(changes(&shallow), 0);
+    assert!(changes(&deep) > 10);
+}
@@ -0,0 +1,309 @@
+//! Escape-time fractals: sets drawn by iterating a function from each point
+//! and counting how long the orbit takes to fly off.
+//!
+//! Each fractal only says where its orbit starts and how it steps; the
+//! escape loops are written once, as provided methods, and compiled afresh
+//! for each fractal so the step is inlined.
+
+use num::Complex;
+
+use crate::{parse_complex, simd};
+
+pub trait Fractal: Send + Sync {
+    /// The orbit's first value, and the constant added at each step, for the
+    /// point `point` in the view.
+    fn start(&self, point: Complex<f64>) -> (Complex<f64>, Complex<f64>);
+
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64>;
+
+    /// The power `step` raises `z` to, which sets how fast escaping orbits
+    /// grow. Smooth counts depend on it.
+    fn degree(&self) -> f64 {
+        2.0
+    }
+
+    /// How far from the origin an orbit must get to be sure it will fly off.
+    /// Two, for squaring; orbits grow more slowly for powers below two, so
+    /// they need to get further, `2^(1/(degree - 1))`, before growth wins
+    /// out over anything `c` can add.
+    fn escape_radius(&self) -> f64 {
+        2.0_f64.max(2.0_f64.powf(1.0 / (self.degree() - 1.0)))
+    }
+
+    /// Where to center the view if we're not told, to see the whole set.
+    fn home(&self) -> Complex<f64> {
+        Complex { re: 0.0, im: 0.0 }
+    }
+
+    /// Try to determine if `point` is in the set, using at most `limit`
+    /// iterations to decide.
+    ///
+    /// If `point` is not a member, return `Some(i)`, where `i` is the number of
+    /// iterations it took for the orbit to leave the circle of radius
+    /// `escape_radius()` centered on the origin. If `point` seems to be a member (more
+    /// precisely, if we reached the iteration limit without being able to
+    /// prove that it is not a member), return None.
+    fn escape_time(&self, point: Complex<f64>, limit: usize) -> Option<usize> {
+        let radius_sqr = self.escape_radius().powi(2);
+        let (mut z, c) = self.start(point);
+        for i in 0..limit {
+            if z.norm_sqr() > radius_sqr {
+                return Some(i);
+            }
+            z = self.step(z, c);
+        }
+        None
+    }
+
+    /// Like `escape_time`, but with a fractional count worked out from how far
+    /// past the escape radius `z` got, so that colours can vary smoothly rather
+    /// than in bands.
+    fn smooth_escape_time(&self, point: Complex<f64>, limit: usize) -> Option<f64> {
+        // Escaping past a larger radius than `escape_radius` makes the
+        // estimate smoother; any point that gets beyond that escapes soon
+        // anyway.
+        const RADIUS_SQR: f64 = 256.0 * 256.0;
+
+        let radius_sqr = RADIUS_SQR.max(self.escape_radius().powi(2));
+        let (mut z, c) = self.start(point);
+        for i in 0..limit {
+            let norm_sqr = z.norm_sqr();
+            if norm_sqr > radius_sqr {
+                let log_modulus = norm_sqr.ln() / 2.0;
+                return Some((i as f64 + 1.0 - log_modulus.ln() / self.degree().ln()).max(0.0));
+            }
+            z = self.step(z, c);
+        }
+        None
+    }
+
+    /// Fill `counts` with the escape count of each of `points`, from
+    /// `smooth_escape_time` if `smooth` is true or `escape_time` if not.
+    /// Fractals with a quicker way to do many points at once override this.
+    fn escape_times(
+        &self,
+        points: &[Complex<f64>],
+        limit: usize,
+        smooth: bool,
+        counts: &mut [Option<f64>],
+    ) {
+        for (&point, count) in points.iter().zip(counts) {
+            *count = if smooth {
+                self.smooth_escape_time(point, limit)
+            } else {
+                self.escape_time(point, limit).map(|count| count as f64)
+            };
+        }
+    }
+}
+
+/// `z * z + c`, starting from zero, with `c` the point.
+pub struct Mandelbrot;
+
+impl Fractal for Mandelbrot {
+    fn start(&self, point: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        (Complex { re: 0.0, im: 0.0 }, point)
+    }
+
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        z * z + c
+    }
+
+    fn home(&self) -> Complex<f64> {
+        Complex { re: -0.5, im: 0.0 }
+    }
+
+    fn escape_times(
+        &self,
+        points: &[Complex<f64>],
+        limit: usize,
+        smooth: bool,
+        counts: &mut [Option<f64>],
+    ) {
+        simd::escape_times(points, limit, smooth, counts);
+    }
+}
+
+/// `z * z + c` again, but starting from the point, with `c` fixed. Each `c`
+/// gives a different set; those for `c` inside the Mandelbrot set are
+/// connected.
+pub struct Julia {
+    pub c: Complex<f64>,
+}
+
+impl Fractal for Julia {
+    fn start(&self, point: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        (point, self.c)
+    }
+
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        z * z + c
+    }
+}
+
+/// Like the Mandelbrot set, but folding `z` into the first quadrant before
+/// squaring. With the imaginary axis pointing up, as ours does, the ship
+/// sails upside down.
+pub struct BurningShip;
+
+impl Fractal for BurningShip {
+    fn start(&self, point: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        (Complex { re: 0.0, im: 0.0 }, point)
+    }
+
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let folded = Complex {
+            re: z.re.abs(),
+            im: z.im.abs(),
+        };
+        folded * folded + c
+    }
+
+    fn home(&self) -> Complex<f64> {
+        Complex { re: -0.5, im: -0.5 }
+    }
+}
+
+/// Like the Mandelbrot set, but squaring `z`'s conjugate.
+pub struct Tricorn;
+
+impl Fractal for Tricorn {
+    fn start(&self, point: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        (Complex { re: 0.0, im: 0.0 }, point)
+    }
+
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let conjugate = z.conj();
+        conjugate * conjugate + c
+    }
+
+    fn home(&self) -> Complex<f64> {
+        Complex { re: -0.3, im: 0.0 }
+    }
+}
+
+/// `z` to the power `power`, plus `c`, starting from zero. Power two is the
+/// Mandelbrot set.
+pub struct Multibrot {
+    pub power: f64,
+}
+
+impl Fractal for Multibrot {
+    fn start(&self, point: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        (Complex { re: 0.0, im: 0.0 }, point)
+    }
+
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        // Whole powers by repeated multiplication are both quicker and exact.
+        if self.power.fract() == 0.0 {
+            z.powi(self.power as i32) + c
+        } else {
+            z.powf(self.power) + c
+        }
+    }
+
+    fn degree(&self) -> f64 {
+        self.power
+    }
+}
+
+/// Parse a fractal's name: `mandelbrot`, `julia:RE,IM`, `burning-ship`,
+/// `tricorn` or `multibrot:POWER`.
+pub fn parse_fractal(s: &str) -> Result<Box<dyn Fractal>, String> {
+    let (name, parameter) = match s.split_once(':') {
+        Some((name, parameter)) => (name, Some(parameter)),
+        None => (s, None),
+    };
+    match (name, parameter) {
+        ("mandelbrot", None) => Ok(Box::new(Mandelbrot)),
+        ("burning-ship", None) => Ok(Box::new(BurningShip)),
+        ("tricorn", None) => Ok(Box::new(Tricorn)),
+        ("julia", Some(c)) => match parse_complex(c) {
+            Some(c) => Ok(Box::new(Julia { c })),
+            None => Err(format!("bad Julia constant {c:?}, expected RE,IM")),
+        },
+        ("multibrot", Some(power)) => match power.parse::<f64>() {
+            // At one or below, orbits don't run off to infinity the way the
+            // escape test assumes. Just above, they take so long to get past
+            // `escape_radius` that most points run out of iterations.
+            Ok(power) if power > 1.0 && power.is_finite() => Ok(Box::new(Multibrot { power })),
+            _ => Err(format!(
+                "bad Multibrot power {power:?}, expected a number above 1"
+            )),
+        },
+        ("julia", None) => Err("julia needs a constant, as in julia:-0.8,0.156".to_string()),
+        ("multibrot", None) => Err("multibrot needs a power, as in multibrot:3".to_string()),
+        _ => Err(format!("unknown fractal {s:?}")),
+    }
+}
+
+#[test]
+fn test_smooth_escape_time() {
+    assert_eq!(
+        Mandelbrot.smooth_escape_time(Complex { re: 0.0, im: 0.0 }, 255),
+        None
+    );
+
+    // Along the real axis, past the cusp at 0.25, the count falls steadily
+    // without jumping from band to band.
+    let counts: Vec<f64> = (0..2000)
+        .map(|i| {
+            let c = Complex {
+                re: 0.3 + i as f64 * 0.0001,
+                im: 0.0,
+            };
+            Mandelbrot.smooth_escape_time(c, 255).unwrap()
+        })
+        .collect();
+    for pair in counts.windows(2) {
+        assert!(pair[1] <= pair[0] && pair[0] - pair[1] < 0.1);
+    }
+}
+
+#[test]
+fn test_fractals() {
+    let point = |re, im| Complex { re, im };
+
+    // With c at zero, the Julia set is the unit disc.
+    let disc = Julia { c: point(0.0, 0.0) };
+    assert_eq!(disc.escape_time(point(0.6, -0.6), 100), None);
+    assert!(disc.escape_time(point(0.8, -0.8), 100).is_some());
+
+    let square = Multibrot { power: 2.0 };
+    let samples = [point(-0.75, 0.1), point(0.3, 0.5), point(-1.5, 0.0)];
+    for &c in &samples {
+        assert_eq!(square.escape_time(c, 255), Mandelbrot.escape_time(c, 255));
+    }
+
+    // The Mandelbrot set and tricorn are mirrored by the real axis; the
+    // burning ship isn't.
+    let c = point(-1.76, 0.03);
+    assert_eq!(
+        Tricorn.escape_time(c, 255),
+        Tricorn.escape_time(c.conj(), 255)
+    );
+    let hull = point(-0.5, -0.5);
+    assert_eq!(BurningShip.escape_time(hull, 255), None);
+    assert!(BurningShip.escape_time(hull.conj(), 255).is_some());
+
+    // Past the escape radius, `z` only grows, however large `c` is in the
+    // set, which is never further out than the radius either.
+    for power in [1.2, 1.5, 2.0, 3.0, 7.5] {
+        let multibrot = Multibrot { power };
+        let radius = multibrot.escape_radius();
+        let z = point(radius * 1.001, 0.0);
+        let c = point(-radius, 0.0);
+        assert!(multibrot.step(z, c).norm() > z.norm(), "power {power}");
+    }
+    assert_eq!(Mandelbrot.escape_radius(), 2.0);
+    assert_eq!(Multibrot { power: 3.0 }.escape_radius(), 2.0);
+    assert_eq!(Multibrot { power: 1.5 }.escape_radius(), 4.0);
+
+    assert!(parse_fractal("julia:-0.8,0.156").is_ok());
+    assert!(parse_fractal("multibrot:3").is_ok());
+    assert!(parse_fractal("burning-ship").is_ok());
+    assert!(parse_fractal("julia").is_err());
+    assert!(parse_fractal("multibrot:0.5").is_err());
+    assert!(parse_fractal("mandelbrot:2").is_err());
+    assert!(parse_fractal("sierpinski").is_err());
+}
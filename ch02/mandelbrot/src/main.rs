@@ -1,8 +1,40 @@
-use std::{env, fs::File, str::FromStr};
+mod animate;
+mod antialias;
+mod config;
+mod deep;
+mod fractal;
+mod palette;
+mod parallel;
+mod simd;
 
-use image::{codecs::png::PngEncoder, ColorType, ImageEncoder, ImageError};
+use std::{
+    env,
+    fs::{self, File},
+    io::BufWriter,
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
+
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::PngEncoder,
+        pnm::{PnmEncoder, PnmSubtype, SampleEncoding},
+        tiff::TiffEncoder,
+    },
+    ColorType, ImageEncoder, ImageError,
+};
 use num::Complex;
 
+use animate::Animation;
+use antialias::Sampling;
+use config::{Config, Format};
+use fractal::Fractal;
+use palette::Mapping;
+
+// The loop the book starts from; it never finishes, so nothing calls it.
+#[allow(dead_code)]
 fn complex_square_add_loop(c: Complex<f64>) {
     let mut z = Complex { re: 0.0, im: 0.0 };
     loop {
@@ -10,24 +42,6 @@ fn complex_square_add_loop(c: Complex<f64>) {
     }
 }
 
-/// Try to determine if `c` is in the Mandelbrot set, using at most `limit` iterations to decide.
-///
-/// If `c` is not a member, return `Some(i)`, where `i` is the number of
-/// iterations ti took for `c` to leave the circle of radius two centered on the
-/// origin. If `c` seems to be a member (more precisely, if we reached the
-/// iteration limit without being able to prove that `c` is not a member),
-/// return None.
-fn escape_time(c: Complex<f64>, limit: usize) -> Option<usize> {
-    let mut z = Complex { re: 0.0, im: 0.0 };
-    for i in 0..limit {
-        if z.norm_sqr() > 4.0 {
-            return Some(i);
-        }
-        z = z * z + c;
-    }
-    None
-}
-
 fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
     match s.find(separator) {
         None => None,
@@ -97,73 +111,267 @@ fn test_pixel_to_point() {
     );
 }
 
+/// Like `pixel_to_point`, but for a spot anywhere in the image, not just
+/// a pixel's corner, for taking several samples per pixel.
+fn subpixel_to_point(
+    bounds: (usize, usize),
+    pixel: (f64, f64),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+) -> Complex<f64> {
+    let (width, height) = (
+        lower_right.re - upper_left.re,
+        upper_left.im - lower_right.im,
+    );
+    Complex {
+        re: upper_left.re + pixel.0 * width / bounds.0 as f64,
+        im: upper_left.im - pixel.1 * height / bounds.1 as f64,
+    }
+}
+
+#[test]
+fn test_subpixel_to_point() {
+    let (upper_left, lower_right) = (Complex { re: -1.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    assert_eq!(
+        subpixel_to_point((100, 200), (25.0, 175.0), upper_left, lower_right),
+        pixel_to_point((100, 200), (25, 175), upper_left, lower_right)
+    );
+    assert_eq!(
+        subpixel_to_point((8, 8), (2.5, 6.5), upper_left, lower_right),
+        Complex {
+            re: -0.375,
+            im: -0.625
+        }
+    );
+}
+
+/// Fill `counts` with the escape count of each pixel, or `None` for pixels
+/// that didn't escape within `limit` iterations. Colouring is left to the
+/// palette, which may need to see every count first.
 fn render(
-    pixels: &mut [u8],
+    fractal: &dyn Fractal,
+    counts: &mut [Option<f64>],
     bounds: (usize, usize),
     upper_left: Complex<f64>,
     lower_right: Complex<f64>,
+    limit: usize,
+    smooth: bool,
 ) {
-    assert!(pixels.len() == bounds.0 * bounds.1);
+    assert!(counts.len() == bounds.0 * bounds.1);
 
     for row in 0..bounds.1 {
-        for column in 0..bounds.0 {
-            let point = pixel_to_point(bounds, (column, row), upper_left, lower_right);
-            pixels[row * bounds.0 + column] = match escape_time(point, 255) {
-                None => 0,
-                Some(count) => 255 - count as u8,
+        let points: Vec<_> = (0..bounds.0)
+            .map(|column| pixel_to_point(bounds, (column, row), upper_left, lower_right))
+            .collect();
+        let counts = &mut counts[row * bounds.0..(row + 1) * bounds.0];
+        fractal.escape_times(&points, limit, smooth, counts);
+    }
+}
+
+fn write_image(
+    filename: &Path,
+    pixels: &[u8],
+    bounds: (usize, usize),
+    format: Format,
+) -> Result<(), ImageError> {
+    let output = BufWriter::new(File::create(filename)?);
+
+    let (width, height) = (bounds.0 as u32, bounds.1 as u32);
+    match format {
+        Format::Png => {
+            PngEncoder::new(output).write_image(pixels, width, height, ColorType::Rgb8)?
+        }
+        Format::Jpeg => JpegEncoder::new_with_quality(output, 90).write_image(
+            pixels,
+            width,
+            height,
+            ColorType::Rgb8,
+        )?,
+        Format::Ppm => PnmEncoder::new(output)
+            .with_subtype(PnmSubtype::Pixmap(SampleEncoding::Binary))
+            .write_image(pixels, width, height, ColorType::Rgb8)?,
+        Format::Tiff => {
+            TiffEncoder::new(output).write_image(pixels, width, height, ColorType::Rgb8)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Render the view from `upper_left` to `lower_right` as RGB pixels, with
+/// the rest of the settings from `config`.
+fn render_image(config: &Config, upper_left: Complex<f64>, lower_right: Complex<f64>) -> Vec<u8> {
+    let Config {
+        bounds,
+        max_iter,
+        smooth,
+        ..
+    } = *config;
+
+    let orbit = config
+        .deep
+        .as_ref()
+        .map(|view| view.reference_orbit(max_iter));
+    // Fill `counts` with the escape counts at `spots` in the image, in pixels
+    // from the upper left, letting the fractal do them together if it can.
+    let escape = |spots: &[(f64, f64)], counts: &mut [Option<f64>]| match (&config.deep, &orbit) {
+        (Some(view), Some(orbit)) => {
+            for (&spot, count) in spots.iter().zip(counts) {
+                *count = deep::escape_time(orbit, view.offset(spot), max_iter, smooth);
+            }
+        }
+        _ => {
+            let points: Vec<_> = spots
+                .iter()
+                .map(|&spot| subpixel_to_point(bounds, spot, upper_left, lower_right))
+                .collect();
+            config
+                .fractal
+                .escape_times(&points, max_iter, smooth, counts);
+        }
+    };
+
+    // One sample per pixel, at its upper left corner.
+    let count_pixels = || {
+        let mut counts = vec![None; bounds.0 * bounds.1];
+
+        // render(&mut pixels, bounds, upper_left, lower_right);
+        let stats = parallel::render_rows(&mut counts, bounds.0, config.threads, |row, counts| {
+            if config.deep.is_some() {
+                let spots: Vec<_> = (0..bounds.0)
+                    .map(|column| (column as f64, row as f64))
+                    .collect();
+                escape(&spots, counts);
+                return;
+            }
+
+            let row_upper_left = pixel_to_point(bounds, (0, row), upper_left, lower_right);
+            let row_lower_right =
+                pixel_to_point(bounds, (bounds.0, row + 1), upper_left, lower_right);
+            render(
+                &*config.fractal,
+                counts,
+                (bounds.0, 1),
+                row_upper_left,
+                row_lower_right,
+                max_iter,
+                smooth,
+            );
+        });
+        (counts, stats)
+    };
+
+    let start = Instant::now();
+    let (pixels, stats) = match config.sampling {
+        Sampling::Single | Sampling::Adaptive(_) => {
+            let (counts, mut stats) = count_pixels();
+            let painter = config.palette.painter(&counts, max_iter);
+            let mut pixels = painter.paint(&counts);
+            if let Sampling::Adaptive(n) = config.sampling {
+                let edges = antialias::edges(&pixels, bounds);
+                let (colours, more_stats) =
+                    antialias::sample_pixels(&edges, n, false, config.threads, &painter, escape);
+                antialias::blend_refined(&mut pixels, bounds.0, &edges, &colours);
+                if config.stats {
+                    eprintln!("refined {} of {} pixels", edges.len(), bounds.0 * bounds.1);
+                }
+                stats.extend(more_stats);
+            }
+            (pixels, stats)
+        }
+        Sampling::Grid(n) | Sampling::Jittered(n) => {
+            // Histogram mapping must see how the counts are spread before it
+            // can colour any. One sample per pixel shows that well enough, for
+            // a fraction of the memory keeping every sample would take.
+            let (counts, mut stats) = match config.palette.mapping {
+                Mapping::Histogram => count_pixels(),
+                _ => (vec![], vec![]),
             };
+            let painter = config.palette.painter(&counts, max_iter);
+
+            let every_pixel: Vec<_> = (0..bounds.1)
+                .flat_map(|y| (0..bounds.0).map(move |x| (x, y)))
+                .collect();
+            let jitter = matches!(config.sampling, Sampling::Jittered(_));
+            let (pixels, more_stats) =
+                antialias::sample_pixels(&every_pixel, n, jitter, config.threads, &painter, escape);
+            stats.extend(more_stats);
+            (pixels, stats)
+        }
+    };
+
+    if config.stats {
+        // With adaptive sampling, or histogram mapping of several samples,
+        // each thread reports twice, once per pass.
+        for (i, thread) in stats.iter().enumerate() {
+            eprintln!(
+                "thread {}: {} rows in {:.3}s",
+                i % config.threads,
+                thread.rows,
+                thread.busy.as_secs_f64()
+            );
         }
+        eprintln!("rendered in {:.3}s", start.elapsed().as_secs_f64());
     }
+    pixels
 }
 
-fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize)) -> Result<(), ImageError> {
-    let output = File::create(filename)?;
+/// Render each of `animation`'s frames that isn't already in the output
+/// directory.
+fn animate(config: &Config, animation: &Animation) -> Result<(), ImageError> {
+    let directory = Path::new(&config.output);
+    fs::create_dir_all(directory)?;
+    let extension = config.format.extension();
 
-    let encoder = PngEncoder::new(output);
-    encoder.write_image(pixels, bounds.0 as u32, bounds.1 as u32, ColorType::L8)?;
+    let mut done = 0;
+    for frame in 0..animation.frames {
+        let path = animation.frame_path(directory, frame, extension);
+        if path.exists() {
+            done += 1;
+            continue;
+        }
+        if done > 0 {
+            eprintln!("{done} frames already done, resuming");
+            done = 0;
+        }
 
+        let (center, zoom) = animation.view(frame);
+        let (upper_left, lower_right) = config::corners(center, zoom, config.bounds);
+        let pixels = render_image(config, upper_left, lower_right);
+
+        let partial = path.with_extension(format!("{extension}.partial"));
+        write_image(&partial, &pixels, config.bounds, config.format)?;
+        fs::rename(&partial, &path)?;
+        eprintln!("frame {} of {}", frame + 1, animation.frames);
+    }
     Ok(())
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() != 5 {
-        eprintln!("Usage: {} FILE PIXELS UPPERLEFT LOWERRIGHT", args[0]);
-        eprintln!(
-            "Example: {} mandel.png 1000x750 -1.20,0.35 -1,0.20",
-            args[0]
-        );
-        std::process::exit(1);
-    }
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{message}\n{}", config::USAGE);
+            std::process::exit(1);
+        }
+    };
 
-    let bounds = parse_pair(&args[2], 'x').expect("error parsing image dimensions");
-    let upper_left = parse_complex(&args[3]).expect("error parsing upper left corner point");
-    let lower_right = parse_complex(&args[4]).expect("error parsing lower right corner point");
-
-    let mut pixels = vec![0; bounds.0 * bounds.1];
-
-    // render(&mut pixels, bounds, upper_left, lower_right);
-    let threads = 8;
-    let rows_per_band = bounds.1 / threads + 1;
-    {
-        let bands: Vec<&mut [u8]> = pixels.chunks_mut(rows_per_band * bounds.0).collect();
-        crossbeam::scope(|spawner| {
-            for (i, band) in bands.into_iter().enumerate() {
-                let top = rows_per_band * i;
-                let height = band.len() / bounds.0;
-                let band_bounds = (bounds.0, height);
-                let band_upper_left = pixel_to_point(bounds, (0, top), upper_left, lower_right);
-                let band_lower_right =
-                    pixel_to_point(bounds, (bounds.0, top + height), upper_left, lower_right);
-
-                spawner.spawn(move |_| {
-                    render(band, band_bounds, band_upper_left, band_lower_right);
-                });
-            }
-        })
-        .unwrap();
+    if let Some(animation) = &config.animation {
+        if let Err(error) = animate(&config, animation) {
+            eprintln!("error writing frames to {}: {error}", config.output);
+            std::process::exit(1);
+        }
+        return;
     }
 
-    write_image(&args[1], &pixels, bounds).expect("error writing PNG file");
+    let pixels = render_image(&config, config.upper_left, config.lower_right);
+    if let Err(error) = write_image(
+        Path::new(&config.output),
+        &pixels,
+        config.bounds,
+        config.format,
+    ) {
+        eprintln!("error writing {}: {error}", config.output);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,340 @@
+//! Turning escape counts into colours.
+//!
+//! A `Palette` is a `Gradient` of colours plus a `Mapping` that decides where
+//! on the gradient each escape count lands. Counts can be fractional, so
+//! smooth counts blend across the bands whole counts would leave.
+
+use std::str::FromStr;
+
+pub type Rgb = [u8; 3];
+
+/// Colours at positions from 0.0 to 1.0, blended linearly in between.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gradient {
+    stops: Vec<(f64, Rgb)>,
+}
+
+impl Gradient {
+    /// `stops` must be in order of position, from 0.0 to 1.0.
+    pub fn new(stops: Vec<(f64, Rgb)>) -> Gradient {
+        assert!(!stops.is_empty(), "a gradient needs at least one colour");
+        assert!(
+            stops.windows(2).all(|pair| pair[0].0 <= pair[1].0),
+            "gradient stops must be in order"
+        );
+        Gradient { stops }
+    }
+
+    /// One of the built-in gradients: `grey`, `fire`, `ocean` or `ultra`.
+    pub fn named(name: &str) -> Option<Gradient> {
+        let stops = match name {
+            // The original renderer's look: white for quick escapes, fading
+            // to black.
+            "grey" => vec![(0.0, [255, 255, 255]), (1.0, [0, 0, 0])],
+            "fire" => vec![
+                (0.0, [0, 0, 0]),
+                (0.3, [180, 20, 0]),
+                (0.6, [255, 160, 0]),
+                (0.85, [255, 240, 120]),
+                (1.0, [255, 255, 255]),
+            ],
+            "ocean" => vec![
+                (0.0, [0, 5, 30]),
+                (0.4, [0, 60, 160]),
+                (0.75, [40, 200, 230]),
+                (1.0, [240, 255, 255]),
+            ],
+            // Starts and ends on the same colour, so it suits cyclic mapping.
+            "ultra" => vec![
+                (0.0, [0, 7, 100]),
+                (0.16, [32, 107, 203]),
+                (0.42, [237, 255, 255]),
+                (0.6425, [255, 170, 0]),
+                (0.8575, [0, 2, 0]),
+                (1.0, [0, 7, 100]),
+            ],
+            _ => return None,
+        };
+        Some(Gradient::new(stops))
+    }
+
+    pub fn colour_at(&self, position: f64) -> Rgb {
+        let position = position.clamp(0.0, 1.0);
+        let next = match self.stops.iter().position(|&(at, _)| at >= position) {
+            None => return self.stops[self.stops.len() - 1].1,
+            Some(0) => return self.stops[0].1,
+            Some(next) => next,
+        };
+
+        let (start, from) = self.stops[next - 1];
+        let (end, to) = self.stops[next];
+        let fraction = (position - start) / (end - start);
+        let mut colour = [0; 3];
+        for channel in 0..3 {
+            let blend =
+                from[channel] as f64 + (to[channel] as f64 - from[channel] as f64) * fraction;
+            colour[channel] = blend.round() as u8;
+        }
+        colour
+    }
+}
+
+impl FromStr for Gradient {
+    type Err = String;
+
+    /// Parse the name of a built-in gradient, or stops like
+    /// `0:000764,0.5:ffaa00,1:000764`: positions from 0 to 1, in order, each
+    /// with a colour as six hex digits.
+    fn from_str(s: &str) -> Result<Gradient, String> {
+        if let Some(gradient) = Gradient::named(s) {
+            return Ok(gradient);
+        }
+        if !s.contains(':') {
+            return Err(format!(
+                "unknown palette {:?} (expected grey, fire, ocean, ultra or POSITION:RRGGBB,...)",
+                s
+            ));
+        }
+
+        let mut stops = vec![];
+        for stop in s.split(',') {
+            let parsed = stop
+                .split_once(':')
+                .and_then(|(position, colour)| Some((position.parse().ok()?, parse_rgb(colour)?)));
+            match parsed {
+                Some((position, colour)) if (0.0..=1.0).contains(&position) => {
+                    stops.push((position, colour))
+                }
+                _ => {
+                    return Err(format!(
+                    "bad gradient stop {:?} (expected POSITION:RRGGBB, with POSITION from 0 to 1)",
+                    stop
+                ))
+                }
+            }
+        }
+        if !stops.windows(2).all(|pair| pair[0].0 <= pair[1].0) {
+            return Err(format!("gradient stops out of order in {:?}", s));
+        }
+        Ok(Gradient::new(stops))
+    }
+}
+
+/// Parse a colour written `RRGGBB` in hex.
+fn parse_rgb(hex: &str) -> Option<Rgb> {
+    if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |at: usize| u8::from_str_radix(&hex[at..at + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[test]
+fn test_colour_at() {
+    let gradient = Gradient::new(vec![
+        (0.0, [0, 0, 0]),
+        (0.5, [200, 100, 0]),
+        (1.0, [200, 100, 255]),
+    ]);
+    assert_eq!(gradient.colour_at(0.0), [0, 0, 0]);
+    assert_eq!(gradient.colour_at(0.25), [100, 50, 0]);
+    assert_eq!(gradient.colour_at(0.5), [200, 100, 0]);
+    assert_eq!(gradient.colour_at(1.0), [200, 100, 255]);
+    assert_eq!(gradient.colour_at(-3.0), [0, 0, 0]);
+    assert_eq!(gradient.colour_at(7.0), [200, 100, 255]);
+    assert!(Gradient::named("ultra").is_some());
+    assert!(Gradient::named("plaid").is_none());
+}
+
+#[test]
+fn test_parse_gradient() {
+    assert_eq!("fire".parse(), Ok(Gradient::named("fire").unwrap()));
+    assert_eq!(
+        "0:000764,0.5:ffaa00,1:000764".parse(),
+        Ok(Gradient::new(vec![
+            (0.0, [0, 7, 100]),
+            (0.5, [255, 170, 0]),
+            (1.0, [0, 7, 100]),
+        ]))
+    );
+    assert_eq!(
+        "0.5:FFFFFF".parse(),
+        Ok(Gradient::new(vec![(0.5, [255; 3])]))
+    );
+    assert!("plaid".parse::<Gradient>().is_err());
+    assert!("".parse::<Gradient>().is_err());
+    assert!("0:000000,".parse::<Gradient>().is_err());
+    assert!("0:00000".parse::<Gradient>().is_err());
+    assert!("0:+f0000".parse::<Gradient>().is_err());
+    assert!("0:00000g".parse::<Gradient>().is_err());
+    assert!("1.5:000000".parse::<Gradient>().is_err());
+    assert!("NaN:000000".parse::<Gradient>().is_err());
+    assert!("1:000000,0:ffffff".parse::<Gradient>().is_err());
+}
+
+/// How escape counts are placed along the gradient.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mapping {
+    /// Zero at one end, the iteration limit at the other.
+    Linear,
+    /// Run through the whole gradient every `period` iterations. Detail deep
+    /// in the image, where counts are high and close together, stays
+    /// colourful.
+    Cyclic { period: f64 },
+    /// Spread the counts so each colour covers about as many pixels as any
+    /// other, whatever the iteration limit and view.
+    Histogram,
+}
+
+impl FromStr for Mapping {
+    type Err = String;
+
+    /// Parse `linear`, `histogram`, `cyclic`, or `cyclic:PERIOD`.
+    fn from_str(s: &str) -> Result<Mapping, String> {
+        match s {
+            "linear" => Ok(Mapping::Linear),
+            "histogram" => Ok(Mapping::Histogram),
+            "cyclic" => Ok(Mapping::Cyclic { period: 64.0 }),
+            _ => match s.strip_prefix("cyclic:").map(f64::from_str) {
+                Some(Ok(period)) if period > 0.0 => Ok(Mapping::Cyclic { period }),
+                Some(_) => Err(format!("bad cyclic period in {:?}", s)),
+                None => Err(format!(
+                    "unknown colour mapping {:?} (expected linear, cyclic[:PERIOD] or histogram)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+#[test]
+fn test_parse_mapping() {
+    assert_eq!("linear".parse(), Ok(Mapping::Linear));
+    assert_eq!("histogram".parse(), Ok(Mapping::Histogram));
+    assert_eq!("cyclic:20".parse(), Ok(Mapping::Cyclic { period: 20.0 }));
+    assert!("cyclic:-1".parse::<Mapping>().is_err());
+    assert!("zigzag".parse::<Mapping>().is_err());
+}
+
+#[derive(Clone, Debug)]
+pub struct Palette {
+    pub gradient: Gradient,
+    pub mapping: Mapping,
+    /// The colour of points that never escaped.
+    pub inside: Rgb,
+}
+
+impl Palette {
+    /// Something to colour counts with, placing them along the gradient as
+    /// they fall among `counts`, each pixel's escape count or `None` if it
+    /// reached `limit` without escaping. Counts not in `counts` can be
+    /// coloured too, such as those of extra samples; only histogram mapping
+    /// looks at `counts` at all.
+    pub fn painter(&self, counts: &[Option<f64>], limit: usize) -> Painter<'_> {
+        let cumulative = match self.mapping {
+            Mapping::Histogram => cumulative_histogram(counts, limit),
+            _ => vec![],
+        };
+        Painter {
+            palette: self,
+            limit,
+            cumulative,
+        }
+    }
+}
+
+pub struct Painter<'a> {
+    palette: &'a Palette,
+    limit: usize,
+    /// For histogram mapping, from `cumulative_histogram`.
+    cumulative: Vec<f64>,
+}
+
+impl Painter<'_> {
+    /// Colour every pixel, returning RGB bytes.
+    pub fn paint(&self, counts: &[Option<f64>]) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(counts.len() * 3);
+        for &count in counts {
+            pixels.extend_from_slice(&self.colour(count));
+        }
+        pixels
+    }
+
+    pub fn colour(&self, count: Option<f64>) -> Rgb {
+        let Some(count) = count else {
+            return self.palette.inside;
+        };
+        let limit = self.limit;
+        let position = match self.palette.mapping {
+            Mapping::Linear => count / limit as f64,
+            Mapping::Cyclic { period } => (count / period).fract(),
+            Mapping::Histogram => {
+                // Blend between the neighbouring bins, so smooth counts stay
+                // smooth.
+                let cumulative = &self.cumulative;
+                let bin = (count.max(0.0) as usize).min(limit - 1);
+                let fraction = (count - bin as f64).clamp(0.0, 1.0);
+                cumulative[bin] + (cumulative[bin + 1] - cumulative[bin]) * fraction
+            }
+        };
+        self.palette.gradient.colour_at(position)
+    }
+}
+
+/// For each whole count `n` from 0 to `limit`, the fraction of escaping
+/// pixels whose count is below `n`.
+fn cumulative_histogram(counts: &[Option<f64>], limit: usize) -> Vec<f64> {
+    let mut histogram = vec![0_usize; limit];
+    for count in counts.iter().flatten() {
+        histogram[(count.max(0.0) as usize).min(limit - 1)] += 1;
+    }
+
+    let total = histogram.iter().sum::<usize>().max(1) as f64;
+    let mut cumulative = Vec::with_capacity(limit + 1);
+    let mut below = 0;
+    cumulative.push(0.0);
+    for n in histogram {
+        below += n;
+        cumulative.push(below as f64 / total);
+    }
+    cumulative
+}
+
+#[test]
+fn test_paint() {
+    let paint = |palette: &Palette, counts: &[Option<f64>], limit| {
+        palette.painter(counts, limit).paint(counts)
+    };
+    let grey = Palette {
+        gradient: Gradient::named("grey").unwrap(),
+        mapping: Mapping::Linear,
+        inside: [0, 0, 0],
+    };
+    // The original greyscale: 255 - count, black inside the set.
+    assert_eq!(
+        paint(&grey, &[Some(0.0), Some(55.0), None], 255),
+        vec![255, 255, 255, 200, 200, 200, 0, 0, 0]
+    );
+
+    let cyclic = Palette {
+        mapping: Mapping::Cyclic { period: 10.0 },
+        ..grey.clone()
+    };
+    assert_eq!(
+        paint(&cyclic, &[Some(5.0)], 255),
+        paint(&cyclic, &[Some(15.0)], 255)
+    );
+
+    // With a linear mapping, counts from 1 to 5 out of 255 would all be
+    // near-white. Equalised, the step from the common count to the rare
+    // ones spans almost the whole gradient.
+    let histogram = Palette {
+        mapping: Mapping::Histogram,
+        ..grey
+    };
+    let mut counts = vec![Some(1.0); 96];
+    counts.extend([Some(2.0), Some(3.0), Some(4.0), Some(5.0)]);
+    let pixels = paint(&histogram, &counts, 255);
+    let shades: Vec<u8> = pixels.chunks(3).skip(95).map(|rgb| rgb[0]).collect();
+    assert_eq!(shades, vec![255, 10, 8, 5, 3]);
+}
@@ -0,0 +1,99 @@
+//! Sharing an image's rows out among threads as they ask for them.
+//!
+//! Splitting the image into one band per thread leaves the threads whose bands
+//! are mostly outside the set sitting idle while the rest grind through the
+//! interior, where every point runs to the iteration limit. Handing out a row
+//! at a time keeps every thread busy until the last few rows.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// What one thread got through.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadStats {
+    pub rows: usize,
+    /// Time spent rendering, not waiting for rows.
+    pub busy: Duration,
+}
+
+/// Call `render_row(row, pixels)` for every `width`-long row of `pixels`, on
+/// `threads` threads. Returns what each thread did.
+pub fn render_rows<T, F>(
+    pixels: &mut [T],
+    width: usize,
+    threads: usize,
+    render_row: F,
+) -> Vec<ThreadStats>
+where
+    T: Send,
+    F: Fn(usize, &mut [T]) + Sync,
+{
+    assert!(width > 0 && pixels.len().is_multiple_of(width));
+
+    // Each row goes to whichever thread locks the iterator next. The lock is
+    // held just long enough to take a row, a sliver of the time spent on it.
+    let rows = Mutex::new(pixels.chunks_mut(width).enumerate());
+    let render_row = &render_row;
+    let rows = &rows;
+
+    crossbeam::scope(|spawner| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                spawner.spawn(move |_| {
+                    let mut stats = ThreadStats::default();
+                    loop {
+                        let next = rows.lock().unwrap().next();
+                        let Some((row, pixels)) = next else {
+                            break;
+                        };
+                        let start = Instant::now();
+                        render_row(row, pixels);
+                        stats.busy += start.elapsed();
+                        stats.rows += 1;
+                    }
+                    stats
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    })
+    .unwrap()
+}
+
+#[test]
+fn test_render_rows() {
+    let mut pixels = vec![0; 12 * 5];
+    let stats = render_rows(&mut pixels, 5, 3, |row, pixels| {
+        for (column, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = row * 10 + column;
+        }
+    });
+    assert_eq!(stats.len(), 3);
+    assert_eq!(stats.iter().map(|s| s.rows).sum::<usize>(), 12);
+    for (i, pixel) in pixels.iter().enumerate() {
+        assert_eq!(*pixel, i / 5 * 10 + i % 5);
+    }
+}
+
+#[test]
+fn test_slow_rows_are_spread_out() {
+    // The first four rows are slow. In one band per thread they'd all fall to
+    // the first thread; handed out a row at a time, each thread gets one, as
+    // the others take the next row while it's busy.
+    let mut pixels = vec![(); 40];
+    let slow_rows = Mutex::new(vec![]);
+    render_rows(&mut pixels, 1, 4, |row, _| {
+        if row < 4 {
+            slow_rows.lock().unwrap().push(std::thread::current().id());
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    });
+    let threads: std::collections::HashSet<_> =
+        slow_rows.into_inner().unwrap().into_iter().collect();
+    assert_eq!(threads.len(), 4);
+}
@@ -0,0 +1,204 @@
+//! A Mandelbrot escape-time kernel that iterates four points at once.
+//!
+//! `std::simd` is still unstable, so the lanes are `wide`'s `f64x4`, which
+//! compiles to vector instructions where the target has them (SSE2 or AVX on
+//! x86, NEON on ARM, simd128 on WebAssembly) and to plain scalar code
+//! elsewhere. Lanes that have escaped keep their last value instead of
+//! branching away, so every step does the same arithmetic to all four. The
+//! whole group stops once every lane is done.
+//!
+//! Two shortcuts skip most of the work for points inside the set, which
+//! otherwise run all the way to the iteration limit:
+//!
+//! - Points in the main cardioid or the period-2 bulb, the two largest parts
+//!   of the set, are recognised by formula before iterating at all.
+//!
+//! - Orbits inside the set settle into a cycle. We save the orbit's value
+//!   now and then, and a lane that comes back to it is caught in a cycle, so
+//!   it will never escape.
+//!
+//! The benchmarks are in `benches/kernels.rs`; run them with `cargo bench`.
+
+use num::Complex;
+use wide::{f64x4, CmpEq, CmpLe, CmpLt};
+
+/// How many points the kernel iterates together, an `f64x4`'s worth. Four
+/// fill an AVX vector, or two SSE2 or NEON ones, which overlap well; timed
+/// against one lane at a time and eight, four was the fastest.
+const LANES: usize = 4;
+
+/// Escaping past a larger radius than two makes smooth counts smoother, as
+/// in `Fractal::smooth_escape_time`.
+const RADIUS_SQR: f64 = 256.0 * 256.0;
+
+/// How many steps to take between checks for escapes and cycles.
+const BLOCK: usize = 8;
+
+/// How close an orbit must come to its saved value to count as a cycle. Far
+/// smaller than any pixel, so an escaping orbit that merely passes near it
+/// won't be taken for one.
+const PERIOD_EPSILON: f64 = 1e-13;
+
+/// Fill `counts` with the Mandelbrot set escape count of each of `points`,
+/// as `Fractal::escape_time` or, when `smooth` is true,
+/// `Fractal::smooth_escape_time` would give.
+pub fn escape_times(
+    points: &[Complex<f64>],
+    limit: usize,
+    smooth: bool,
+    counts: &mut [Option<f64>],
+) {
+    assert!(points.len() == counts.len());
+
+    for (points, counts) in points.chunks(LANES).zip(counts.chunks_mut(LANES)) {
+        // Fill out a short last group by repeating its last point.
+        let mut c_re = [0.0; LANES];
+        let mut c_im = [0.0; LANES];
+        for lane in 0..LANES {
+            let point = points[lane.min(points.len() - 1)];
+            c_re[lane] = point.re;
+            c_im[lane] = point.im;
+        }
+        let lanes = escape_lanes(c_re, c_im, limit, smooth);
+        counts.copy_from_slice(&lanes[..counts.len()]);
+    }
+}
+
+/// The escape counts for the four points `c_re[i] + c_im[i] i`.
+fn escape_lanes(
+    c_re: [f64; LANES],
+    c_im: [f64; LANES],
+    limit: usize,
+    smooth: bool,
+) -> [Option<f64>; LANES] {
+    let radius_sqr = f64x4::splat(if smooth { RADIUS_SQR } else { 4.0 });
+    let epsilon = f64x4::splat(PERIOD_EPSILON);
+    // Masks are all ones in a lane for true, and all zeros for false.
+    let shortcut =
+        std::array::from_fn(|lane| in_cardioid_or_bulb(c_re[lane], c_im[lane]) as u8 as f64);
+    let (c_re, c_im) = (f64x4::new(c_re), f64x4::new(c_im));
+    let mut inside = f64x4::new(shortcut).cmp_eq(f64x4::ONE);
+    // Lanes still iterating: not known to be inside, and not escaped yet.
+    let mut active = !inside;
+    let mut re = f64x4::ZERO;
+    let mut im = f64x4::ZERO;
+    let mut count = f64x4::ZERO;
+
+    // Nothing matches until the first save.
+    let mut saved_re = f64x4::splat(f64::NAN);
+    let mut saved_im = f64x4::splat(f64::NAN);
+    // Saving at doubling intervals catches a cycle of any period once the
+    // interval has grown past it.
+    let mut next_save = 2 * BLOCK;
+
+    let mut i = 0;
+    while i < limit && active.any() {
+        // Checking whether we're done after every step would cost as much as
+        // the step itself, so take a few at a time.
+        let steps = BLOCK.min(limit - i);
+        for _ in 0..steps {
+            let (x_sqr, y_sqr) = (re * re, im * im);
+            let still = active & (x_sqr + y_sqr).cmp_le(radius_sqr);
+            active = still;
+            let next_im = f64x4::splat(2.0) * re * im + c_im;
+            re = still.blend(x_sqr - y_sqr + c_re, re);
+            im = still.blend(next_im, im);
+            count += still & f64x4::ONE;
+        }
+        i += steps;
+
+        if i == next_save {
+            saved_re = re;
+            saved_im = im;
+            next_save *= 2;
+        } else {
+            let cycled =
+                (re - saved_re).abs().cmp_lt(epsilon) & (im - saved_im).abs().cmp_lt(epsilon);
+            inside |= active & cycled;
+            active &= !cycled;
+        }
+    }
+
+    let done = !(inside | active);
+    let (re, im, count) = (re.to_array(), im.to_array(), count.to_array());
+    std::array::from_fn(|lane| {
+        if done.move_mask() & (1 << lane) == 0 {
+            return None;
+        }
+        Some(if smooth {
+            let log_modulus = (re[lane] * re[lane] + im[lane] * im[lane]).ln() / 2.0;
+            (count[lane] + 1.0 - log_modulus.ln() / 2.0_f64.ln()).max(0.0)
+        } else {
+            count[lane]
+        })
+    })
+}
+
+/// Whether `re + im i` is in the main cardioid or the disc to its left, the
+/// period-2 bulb, both wholly inside the set.
+fn in_cardioid_or_bulb(re: f64, im: f64) -> bool {
+    let im_sqr = im * im;
+    let q = (re - 0.25) * (re - 0.25) + im_sqr;
+    let cardioid = q * (q + (re - 0.25)) <= 0.25 * im_sqr;
+    let bulb = (re + 1.0) * (re + 1.0) + im_sqr <= 1.0 / 16.0;
+    cardioid || bulb
+}
+
+/// A `bounds.0` by `bounds.1` grid of points, `width` wide and centred on
+/// `center`, in rows from the top. For the tests and the benchmarks.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn grid(center: Complex<f64>, width: f64, bounds: (usize, usize)) -> Vec<Complex<f64>> {
+    let spacing = width / bounds.0 as f64;
+    (0..bounds.1)
+        .flat_map(|row| {
+            (0..bounds.0).map(move |column| Complex {
+                re: center.re + (column as f64 - bounds.0 as f64 / 2.0) * spacing,
+                im: center.im + (bounds.1 as f64 / 2.0 - row as f64) * spacing,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_cardioid_and_bulb() {
+    assert!(in_cardioid_or_bulb(0.0, 0.0));
+    assert!(in_cardioid_or_bulb(0.24, 0.0));
+    assert!(in_cardioid_or_bulb(-0.74, 0.0));
+    assert!(in_cardioid_or_bulb(-1.0, 0.24));
+    assert!(!in_cardioid_or_bulb(0.26, 0.0));
+    assert!(!in_cardioid_or_bulb(-1.26, 0.0));
+    // Inside the set, but in a smaller bulb.
+    assert!(!in_cardioid_or_bulb(-0.12, 0.75));
+}
+
+#[test]
+fn test_matches_scalar() {
+    use crate::fractal::{Fractal, Mandelbrot};
+
+    // 101 by 75 points, so the last group is short.
+    let points = grid(Complex { re: -0.5, im: 0.0 }, 3.0, (101, 75));
+    for smooth in [false, true] {
+        let scalar: Vec<_> = points
+            .iter()
+            .map(|&point| {
+                if smooth {
+                    Mandelbrot.smooth_escape_time(point, 500)
+                } else {
+                    Mandelbrot.escape_time(point, 500).map(|count| count as f64)
+                }
+            })
+            .collect();
+        let mut kernel = vec![None; points.len()];
+        escape_times(&points, 500, smooth, &mut kernel);
+
+        // The shortcuts may decide a few points right on the boundary
+        // differently, but nothing that escapes may be called inside.
+        let differ = scalar.iter().zip(&kernel).filter(|(a, b)| a != b).count();
+        assert!(differ * 1000 <= points.len(), "{differ} differ");
+        for (scalar, vector) in scalar.iter().zip(&kernel) {
+            if scalar.is_some() && vector.is_none() {
+                assert!(scalar.unwrap() > 400.0);
+            }
+        }
+    }
+}
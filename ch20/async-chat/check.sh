@@ -0,0 +1,12 @@
+#!/bin/sh
+# Build, lint and test on both runtimes. `cargo test` alone only covers the
+# default async-std backend.
+set -e
+cd "$(dirname "$0")"
+
+for features in "" "--no-default-features --features tokio"; do
+    echo "== async-chat ${features:-(default features)}"
+    cargo build $features
+    cargo clippy --all-targets $features -- -D warnings
+    cargo test $features
+done
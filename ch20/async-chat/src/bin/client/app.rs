@@ -0,0 +1,725 @@
+//! The client's state: the groups we're in, their messages, and the input line.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_chat::{client::Download, utils, AttachmentId, FromClient, FromServer, MessageId};
+
+use crate::{get_next_token, parse_command};
+
+pub const USAGE: &str = "Usage: join GROUP | post GROUP MESSAGE... | edit GROUP ID MESSAGE... \
+    | delete GROUP ID | react GROUP ID REACTION | upload GROUP PATH | download ID [PATH] | quit";
+
+pub struct Pane {
+    pub name: Arc<String>,
+    pub lines: Vec<Entry>,
+    pub unread: usize,
+    /// How many lines up from the bottom the view is scrolled.
+    pub scroll: usize,
+    /// False only for the first pane, which holds notices from the server.
+    pub is_group: bool,
+}
+
+/// A line in a pane: a notice, or a group message that may yet be edited,
+/// deleted or reacted to.
+pub struct Entry {
+    id: Option<MessageId>,
+    text: Arc<String>,
+    edited: bool,
+    deleted: bool,
+    /// Each reaction and how many times it's been made, in order of arrival.
+    reactions: Vec<(Arc<String>, usize)>,
+}
+
+impl Entry {
+    fn new(id: Option<MessageId>, text: Arc<String>) -> Self {
+        Entry {
+            id,
+            text,
+            edited: false,
+            deleted: false,
+            reactions: vec![],
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut line = match self.id {
+            Some(id) => format!("[{}] ", id),
+            None => String::new(),
+        };
+        if self.deleted {
+            line.push_str("(deleted)");
+            return line;
+        }
+        line.push_str(&self.text);
+        if self.edited {
+            line.push_str(" (edited)");
+        }
+        for (reaction, count) in &self.reactions {
+            line.push_str(&format!("  {}:{}", reaction, count));
+        }
+        line
+    }
+}
+
+impl Pane {
+    fn new(name: Arc<String>, is_group: bool) -> Self {
+        Pane {
+            name,
+            lines: vec![],
+            unread: 0,
+            scroll: 0,
+            is_group,
+        }
+    }
+}
+
+pub struct App {
+    pub panes: Vec<Pane>,
+    pub selected: usize,
+    pub input: String,
+    /// A one-line hint shown under the input, like completion candidates.
+    pub status: String,
+    history: Vec<String>,
+    /// Where Up/Down have taken us in `history`, if anywhere.
+    history_pos: Option<usize>,
+    /// The name and hash of each attachment announced to us.
+    attachments: HashMap<AttachmentId, (Arc<String>, String)>,
+    /// Downloads under way, and where to save each one.
+    downloads: HashMap<AttachmentId, (Destination, Download)>,
+}
+
+/// Where to save a download.
+struct Destination {
+    path: PathBuf,
+    /// Whether the user named the path, and so means to replace whatever is
+    /// there. A default path comes from the sender, and mustn't.
+    chosen: bool,
+}
+
+/// What the caller should do after the app has handled some input.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Nothing,
+    Send(FromClient),
+    /// Read the file at `path` and upload it to the group.
+    Upload {
+        group_name: Arc<String>,
+        path: PathBuf,
+    },
+    Quit,
+}
+
+impl App {
+    pub fn new() -> Self {
+        App {
+            panes: vec![Pane::new(Arc::new("*server*".to_string()), false)],
+            selected: 0,
+            input: String::new(),
+            status: String::new(),
+            history: vec![],
+            history_pos: None,
+            attachments: HashMap::new(),
+            downloads: HashMap::new(),
+        }
+    }
+
+    pub fn selected_pane(&self) -> &Pane {
+        &self.panes[self.selected]
+    }
+
+    fn pane_index(&mut self, name: &Arc<String>) -> usize {
+        match self
+            .panes
+            .iter()
+            .position(|pane| pane.is_group && pane.name == *name)
+        {
+            Some(index) => index,
+            None => {
+                self.panes.push(Pane::new(name.clone(), true));
+                self.panes.len() - 1
+            }
+        }
+    }
+
+    fn add_line(&mut self, index: usize, line: Entry) {
+        let pane = &mut self.panes[index];
+        pane.lines.push(line);
+        if index != self.selected {
+            pane.unread += 1;
+        } else if pane.scroll > 0 {
+            // Keep the view still while the user reads back.
+            pane.scroll += 1;
+        }
+    }
+
+    pub fn notice(&mut self, line: String) {
+        self.add_line(0, Entry::new(None, Arc::new(line)));
+    }
+
+    /// The message `id` in `group_name`'s pane, if we still have it.
+    fn message_mut(&mut self, group_name: &Arc<String>, id: MessageId) -> Option<&mut Entry> {
+        let pane = self
+            .panes
+            .iter_mut()
+            .find(|pane| pane.is_group && pane.name == *group_name)?;
+        pane.lines
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.id == Some(id))
+    }
+
+    pub fn handle_packet(&mut self, packet: FromServer) {
+        match packet {
+            FromServer::Message {
+                group_name,
+                id,
+                message,
+            } => {
+                let index = self.pane_index(&group_name);
+                self.add_line(index, Entry::new(Some(id), message));
+            }
+            FromServer::Edited {
+                group_name,
+                id,
+                message,
+            } => {
+                if let Some(entry) = self.message_mut(&group_name, id) {
+                    entry.text = message;
+                    entry.edited = true;
+                }
+            }
+            FromServer::Deleted { group_name, id } => {
+                if let Some(entry) = self.message_mut(&group_name, id) {
+                    entry.deleted = true;
+                }
+            }
+            FromServer::Reacted {
+                group_name,
+                id,
+                reaction,
+            } => {
+                if let Some(entry) = self.message_mut(&group_name, id) {
+                    match entry.reactions.iter_mut().find(|(r, _)| *r == reaction) {
+                        Some((_, count)) => *count += 1,
+                        None => entry.reactions.push((reaction, 1)),
+                    }
+                }
+            }
+            FromServer::Attachment {
+                group_name,
+                attachment_id,
+                file_name,
+                size,
+                sha256,
+            } => {
+                let index = self.pane_index(&group_name);
+                let line = format!(
+                    "[attachment {}] {} ({} bytes) - download {} to save it",
+                    attachment_id, file_name, size, attachment_id
+                );
+                self.add_line(index, Entry::new(None, Arc::new(line)));
+                self.attachments.insert(attachment_id, (file_name, sha256));
+            }
+            FromServer::Uploaded {
+                upload_id,
+                attachment_id,
+            } => self.notice(format!(
+                "upload {} stored as attachment {}",
+                upload_id, attachment_id
+            )),
+            FromServer::DownloadChunk {
+                attachment_id,
+                offset,
+                size,
+                data,
+            } => self.download_chunk(attachment_id, offset, size, &data),
+            FromServer::Error(message) => self.notice(format!("error from server: {}", message)),
+            FromServer::JoinRefused { group_name, reason } => {
+                self.notice(format!("can't join {}: {}", group_name, reason))
+            }
+            FromServer::Shutdown(reason) => {
+                self.notice(format!("server shutting down: {}", reason))
+            }
+            // The connection answers these itself.
+            FromServer::Ping
+            | FromServer::UploadStarted { .. }
+            | FromServer::UploadRefused { .. } => {}
+        }
+    }
+
+    fn download_chunk(&mut self, attachment_id: AttachmentId, offset: u64, size: u64, data: &[u8]) {
+        let (_, download) = match self.downloads.get_mut(&attachment_id) {
+            Some(download) => download,
+            None => return,
+        };
+        let data = match download.add(offset, size, data) {
+            Ok(None) => return,
+            Ok(Some(data)) => data,
+            Err(error) => {
+                self.downloads.remove(&attachment_id);
+                self.notice(format!("attachment {}: {}", attachment_id, error));
+                return;
+            }
+        };
+
+        let (destination, _) = self.downloads.remove(&attachment_id).unwrap();
+        let path = destination.path;
+        let notice = match self.attachments.get(&attachment_id) {
+            None => format!(
+                "attachment {} was never announced, so there's no hash to check it by; not saved",
+                attachment_id
+            ),
+            Some((_, sha256)) if *sha256 != utils::sha256_hex(&data) => format!(
+                "attachment {} doesn't match its hash; not saved",
+                attachment_id
+            ),
+            Some(_) => {
+                let saved = if destination.chosen {
+                    fs::write(&path, &data).map(|()| path.clone())
+                } else {
+                    save_new(&path, attachment_id, &data)
+                };
+                match saved {
+                    Ok(saved) => {
+                        format!("saved attachment {} to {}", attachment_id, saved.display())
+                    }
+                    Err(error) => format!("can't save to {}: {}", path.display(), error),
+                }
+            }
+        };
+        self.notice(notice);
+    }
+
+    /// Where to save `attachment_id` if the user didn't say: its own name, in
+    /// the current directory.
+    fn default_path(&self, attachment_id: AttachmentId) -> PathBuf {
+        let announced = self.attachments.get(&attachment_id);
+        // Take only the last component, so a name can't point elsewhere.
+        let file_name = announced
+            .and_then(|(name, _)| PathBuf::from(name.as_str()).file_name().map(PathBuf::from));
+        file_name.unwrap_or_else(|| PathBuf::from(format!("attachment-{}", attachment_id)))
+    }
+
+    pub fn select(&mut self, index: usize) {
+        self.selected = index % self.panes.len();
+        self.panes[self.selected].unread = 0;
+    }
+
+    pub fn select_next(&mut self) {
+        self.select(self.selected + 1);
+    }
+
+    pub fn select_previous(&mut self) {
+        self.select(self.selected + self.panes.len() - 1);
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        let pane = &mut self.panes[self.selected];
+        pane.scroll = (pane.scroll + lines).min(pane.lines.len().saturating_sub(1));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        let pane = &mut self.panes[self.selected];
+        pane.scroll = pane.scroll.saturating_sub(lines);
+    }
+
+    pub fn type_char(&mut self, c: char) {
+        self.input.push(c);
+        self.history_pos = None;
+    }
+
+    pub fn backspace(&mut self) {
+        self.input.pop();
+        self.history_pos = None;
+    }
+
+    pub fn history_previous(&mut self) {
+        let pos = match self.history_pos {
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+            Some(pos) => pos.saturating_sub(1),
+        };
+        self.history_pos = Some(pos);
+        self.input = self.history[pos].clone();
+    }
+
+    pub fn history_next(&mut self) {
+        match self.history_pos {
+            Some(pos) if pos + 1 < self.history.len() => {
+                self.history_pos = Some(pos + 1);
+                self.input = self.history[pos + 1].clone();
+            }
+            Some(_) => {
+                self.history_pos = None;
+                self.input.clear();
+            }
+            None => {}
+        }
+    }
+
+    /// Complete the group name being typed at the end of the input, as far as
+    /// it's unambiguous. If several groups still match, list them in `status`.
+    pub fn complete(&mut self) {
+        let start = self
+            .input
+            .rfind(char::is_whitespace)
+            .map_or(0, |space| space + 1);
+        let prefix = &self.input[start..];
+        let candidates: Vec<&str> = self
+            .panes
+            .iter()
+            .filter(|pane| pane.is_group && pane.name.starts_with(prefix))
+            .map(|pane| pane.name.as_str())
+            .collect();
+
+        let common = match candidates.split_first() {
+            None => return,
+            Some((first, rest)) => rest.iter().fold(*first, |common, name| {
+                let len = common
+                    .char_indices()
+                    .zip(name.chars())
+                    .take_while(|((_, a), b)| a == b)
+                    .last()
+                    .map_or(0, |((i, a), _)| i + a.len_utf8());
+                &common[..len]
+            }),
+        };
+
+        let mut completed = format!("{}{}", &self.input[..start], common);
+        if candidates.len() == 1 {
+            completed.push(' ');
+            self.status.clear();
+        } else {
+            self.status = candidates.join(" ");
+        }
+        self.input = completed;
+    }
+
+    /// Act on the input line. Lines that aren't commands are posted to the
+    /// selected group.
+    pub fn submit(&mut self) -> Action {
+        let line = std::mem::take(&mut self.input);
+        self.history_pos = None;
+        self.status.clear();
+        if line.trim().is_empty() {
+            return Action::Nothing;
+        }
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        let request = match line.split_whitespace().next() {
+            Some("quit") if line.trim() == "quit" => return Action::Quit,
+            Some("upload") => {
+                let (_, rest) = get_next_token(&line).unwrap();
+                match get_next_token(rest) {
+                    Some((group, path)) if !path.trim().is_empty() => {
+                        return Action::Upload {
+                            group_name: Arc::new(group.to_string()),
+                            path: PathBuf::from(path.trim()),
+                        }
+                    }
+                    _ => {
+                        self.status = USAGE.to_string();
+                        return Action::Nothing;
+                    }
+                }
+            }
+            Some("download") => {
+                let (_, rest) = get_next_token(&line).unwrap();
+                let (id, path) = match get_next_token(rest).map(|(id, path)| (id.parse(), path)) {
+                    Some((Ok(id), path)) => (id, path.trim()),
+                    _ => {
+                        self.status = USAGE.to_string();
+                        return Action::Nothing;
+                    }
+                };
+                if !self.attachments.contains_key(&id) {
+                    self.status = format!("no attachment {} has been announced", id);
+                    return Action::Nothing;
+                }
+                let destination = match path {
+                    "" => Destination {
+                        path: self.default_path(id),
+                        chosen: false,
+                    },
+                    path => Destination {
+                        path: PathBuf::from(path),
+                        chosen: true,
+                    },
+                };
+                self.downloads
+                    .insert(id, (destination, Download::default()));
+                FromClient::Download { attachment_id: id }
+            }
+            Some("join" | "post" | "edit" | "delete" | "react") => match parse_command(&line) {
+                Some(request) => request,
+                None => {
+                    self.status = USAGE.to_string();
+                    return Action::Nothing;
+                }
+            },
+            _ if self.selected_pane().is_group => FromClient::Post {
+                group_name: self.selected_pane().name.clone(),
+                message: Arc::new(line),
+            },
+            _ => {
+                self.status = "Join a group first: join GROUP".to_string();
+                return Action::Nothing;
+            }
+        };
+
+        if let FromClient::Join { group_name } = &request {
+            let index = self.pane_index(group_name);
+            self.select(index);
+        }
+        Action::Send(request)
+    }
+}
+
+/// Write `data` to a new file at `path`, or, if something is there already,
+/// at `attachment-ID`, `attachment-ID-2` and so on beside it. Never replaces
+/// an existing file.
+fn save_new(path: &Path, attachment_id: AttachmentId, data: &[u8]) -> io::Result<PathBuf> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let fallbacks = (1..100).map(|n| match n {
+        1 => directory.join(format!("attachment-{}", attachment_id)),
+        n => directory.join(format!("attachment-{}-{}", attachment_id, n)),
+    });
+    for candidate in iter::once(path.to_path_buf()).chain(fallbacks) {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(mut file) => {
+                file.write_all(data)?;
+                return Ok(candidate);
+            }
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "every name for it is taken",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(group: &str, id: MessageId, text: &str) -> FromServer {
+        FromServer::Message {
+            group_name: Arc::new(group.to_string()),
+            id,
+            message: Arc::new(text.to_string()),
+        }
+    }
+
+    fn submit(app: &mut App, line: &str) -> Action {
+        app.input = line.to_string();
+        app.submit()
+    }
+
+    #[test]
+    fn test_unread_counts() {
+        let mut app = App::new();
+        submit(&mut app, "join Dogs");
+        submit(&mut app, "join Cats");
+        assert_eq!(app.selected_pane().name.as_str(), "Cats");
+
+        app.handle_packet(message("Dogs", 1, "woof"));
+        app.handle_packet(message("Dogs", 2, "woof woof"));
+        app.handle_packet(message("Cats", 1, "meow"));
+        assert_eq!(app.panes[1].unread, 2);
+        assert_eq!(app.panes[2].unread, 0);
+
+        app.select_previous();
+        assert_eq!(app.selected_pane().name.as_str(), "Dogs");
+        assert_eq!(app.panes[1].unread, 0);
+    }
+
+    #[test]
+    fn test_submit() {
+        let mut app = App::new();
+        assert_eq!(submit(&mut app, "hello"), Action::Nothing);
+        assert_eq!(
+            submit(&mut app, "join Dogs"),
+            Action::Send(FromClient::Join {
+                group_name: Arc::new("Dogs".to_string())
+            })
+        );
+        assert_eq!(
+            submit(&mut app, "Samoyeds rock!"),
+            Action::Send(FromClient::Post {
+                group_name: Arc::new("Dogs".to_string()),
+                message: Arc::new("Samoyeds rock!".to_string()),
+            })
+        );
+        assert_eq!(submit(&mut app, "join"), Action::Nothing);
+        assert_eq!(submit(&mut app, "quit"), Action::Quit);
+    }
+
+    #[test]
+    fn test_message_updates() {
+        let mut app = App::new();
+        submit(&mut app, "join Dogs");
+        app.handle_packet(message("Dogs", 1, "Samoyeds rock!"));
+        app.handle_packet(message("Dogs", 2, "Huskies too."));
+
+        let dogs = Arc::new("Dogs".to_string());
+        app.handle_packet(FromServer::Edited {
+            group_name: dogs.clone(),
+            id: 1,
+            message: Arc::new("Samoyeds rule!".to_string()),
+        });
+        for reaction in ["+1", "+1", "woof"] {
+            app.handle_packet(FromServer::Reacted {
+                group_name: dogs.clone(),
+                id: 1,
+                reaction: Arc::new(reaction.to_string()),
+            });
+        }
+        app.handle_packet(FromServer::Deleted {
+            group_name: dogs.clone(),
+            id: 2,
+        });
+        // Updates to messages we never saw are ignored.
+        app.handle_packet(FromServer::Deleted {
+            group_name: dogs,
+            id: 3,
+        });
+
+        let lines: Vec<String> = app.panes[1].lines.iter().map(Entry::render).collect();
+        assert_eq!(
+            lines,
+            ["[1] Samoyeds rule! (edited)  +1:2  woof:1", "[2] (deleted)"]
+        );
+        assert_eq!(
+            submit(&mut app, "react Dogs 1 +1"),
+            Action::Send(FromClient::React {
+                group_name: Arc::new("Dogs".to_string()),
+                id: 1,
+                reaction: Arc::new("+1".to_string()),
+            })
+        );
+        assert_eq!(submit(&mut app, "delete Dogs one"), Action::Nothing);
+        assert_eq!(app.status, USAGE);
+    }
+
+    #[test]
+    fn test_download() {
+        let mut app = App::new();
+        submit(&mut app, "join Dogs");
+        let data = b"woof woof".to_vec();
+        app.handle_packet(FromServer::Attachment {
+            group_name: Arc::new("Dogs".to_string()),
+            attachment_id: 3,
+            file_name: Arc::new("../bark.txt".to_string()),
+            size: data.len() as u64,
+            sha256: utils::sha256_hex(&data),
+        });
+        assert_eq!(app.default_path(3), PathBuf::from("bark.txt"));
+
+        let path = std::env::temp_dir().join(format!("chat-download-{}", std::process::id()));
+        let command = format!("download 3 {}", path.display());
+        assert_eq!(
+            submit(&mut app, &command),
+            Action::Send(FromClient::Download { attachment_id: 3 })
+        );
+        for (offset, chunk) in [(0, &data[..4]), (4, &data[4..])] {
+            app.handle_packet(FromServer::DownloadChunk {
+                attachment_id: 3,
+                offset,
+                size: data.len() as u64,
+                data: chunk.to_vec(),
+            });
+        }
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            submit(&mut app, "upload Dogs ./bark.txt"),
+            Action::Upload {
+                group_name: Arc::new("Dogs".to_string()),
+                path: PathBuf::from("./bark.txt"),
+            }
+        );
+        assert_eq!(submit(&mut app, "download three"), Action::Nothing);
+
+        // Nothing to check an unannounced attachment against, so don't even
+        // ask for it.
+        assert_eq!(submit(&mut app, "download 4"), Action::Nothing);
+        assert!(app.status.contains("no attachment 4"));
+    }
+
+    #[test]
+    fn test_save_new() {
+        let directory = std::env::temp_dir().join(format!("chat-save-new-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(".bashrc");
+        fs::write(&path, "mine").unwrap();
+
+        // The sender's choice of name doesn't get to replace our file.
+        let saved = save_new(&path, 7, b"theirs").unwrap();
+        assert_eq!(saved, directory.join("attachment-7"));
+        assert_eq!(fs::read(&path).unwrap(), b"mine");
+        assert_eq!(fs::read(&saved).unwrap(), b"theirs");
+        let again = save_new(&path, 7, b"again").unwrap();
+        assert_eq!(again, directory.join("attachment-7-2"));
+
+        let fresh = save_new(&directory.join("new.txt"), 8, b"new").unwrap();
+        assert_eq!(fresh, directory.join("new.txt"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let mut app = App::new();
+        submit(&mut app, "join Dogs");
+        submit(&mut app, "first");
+        submit(&mut app, "second");
+
+        app.history_previous();
+        assert_eq!(app.input, "second");
+        app.history_previous();
+        app.history_previous();
+        app.history_previous();
+        assert_eq!(app.input, "join Dogs");
+        app.history_next();
+        assert_eq!(app.input, "first");
+        app.history_next();
+        app.history_next();
+        assert_eq!(app.input, "");
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut app = App::new();
+        submit(&mut app, "join Dogs");
+        submit(&mut app, "join Dolphins");
+        submit(&mut app, "join Cats");
+
+        app.input = "post C".to_string();
+        app.complete();
+        assert_eq!(app.input, "post Cats ");
+
+        app.input = "post D".to_string();
+        app.complete();
+        assert_eq!(app.input, "post Do");
+        assert_eq!(app.status, "Dogs Dolphins");
+
+        app.input = "post X".to_string();
+        app.complete();
+        assert_eq!(app.input, "post X");
+    }
+}
@@ -0,0 +1,194 @@
+//! The client's link to the server, which outlives any one TCP connection: if
+//! the connection drops, we reconnect and rejoin our groups.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_channel as channel;
+use async_chat::{
+    client::Events, rt, utils::ChatResult, ChatClient, FromClient, FromServer, UploadId,
+};
+use futures_lite::StreamExt;
+
+use crate::Event;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct Connection {
+    address: String,
+    /// The current connection, or `None` while reconnecting.
+    client: Mutex<Option<ChatClient>>,
+    /// The groups we've asked to join, in order, to rejoin after reconnecting.
+    joined: Mutex<Vec<Arc<String>>>,
+    /// Names us to the server on every connection, so the messages we posted
+    /// before a reconnect are still ours to edit and delete.
+    session: String,
+}
+
+impl Connection {
+    pub fn new(address: String) -> Self {
+        Connection {
+            address,
+            client: Mutex::new(None),
+            joined: Mutex::new(vec![]),
+            session: new_session_token(),
+        }
+    }
+
+    /// Send `request` to the server. Joins are remembered even if we're not
+    /// connected right now, and sent when we are. A join for a group we've
+    /// already asked to join isn't sent again, since the server would
+    /// subscribe us twice.
+    pub async fn send(&self, request: &FromClient) -> ChatResult<()> {
+        let client = {
+            // `connect_and_serve` holds this lock while it installs a new
+            // client and lists the groups to rejoin, so a join is sent either
+            // by that rejoin or here, never both.
+            let mut joined = self.joined.lock().unwrap();
+            if let FromClient::Join { group_name } = request {
+                if joined.contains(group_name) {
+                    return Ok(());
+                }
+                joined.push(group_name.clone());
+            }
+            self.client.lock().unwrap().clone()
+        };
+        let client = client.ok_or("not connected to the server, reconnecting")?;
+        client.send(request).await
+    }
+
+    /// Stop rejoining `group_name`, since the server refused it.
+    fn forget(&self, group_name: &Arc<String>) {
+        self.joined
+            .lock()
+            .unwrap()
+            .retain(|joined| joined != group_name);
+    }
+
+    /// Upload `path` to `group_name`, naming it after the file.
+    pub async fn upload(&self, group_name: &str, path: &Path) -> ChatResult<UploadId> {
+        let to_read = path.to_owned();
+        let data = rt::spawn_blocking(move || std::fs::read(to_read)).await?;
+        let file_name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return Err(format!("{} is not a file", path.display()).into()),
+        };
+        self.current()?.upload(group_name, &file_name, &data).await
+    }
+
+    fn current(&self) -> ChatResult<ChatClient> {
+        let client = self.client.lock().unwrap().clone();
+        Ok(client.ok_or("not connected to the server, reconnecting")?)
+    }
+}
+
+/// Stay connected to the server, reconnecting with exponential backoff
+/// whenever the connection drops. Runs until `events` is closed.
+pub async fn keep_connected(connection: Arc<Connection>, events: channel::Sender<Event>) {
+    let mut backoff = MIN_BACKOFF;
+    while !events.is_closed() {
+        let mut connected = false;
+        let result = connect_and_serve(&connection, &events, &mut connected).await;
+        *connection.client.lock().unwrap() = None;
+
+        if connected {
+            backoff = MIN_BACKOFF;
+        }
+        let reason = match result {
+            Ok(()) => "connection closed by server".to_string(),
+            Err(error) if connected => format!("connection lost: {}", error),
+            Err(error) => format!("can't connect to {}: {}", connection.address, error),
+        };
+        let notice = format!("{}; retrying in {:.1}s", reason, backoff.as_secs_f64());
+        let _ignored = events.send(Event::Disconnected(notice)).await;
+
+        rt::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn connect_and_serve(
+    connection: &Connection,
+    events: &channel::Sender<Event>,
+    connected: &mut bool,
+) -> ChatResult<()> {
+    let (client, events_from_server) = ChatClient::connect(&connection.address).await?;
+    let joined = {
+        let joined = connection.joined.lock().unwrap();
+        *connection.client.lock().unwrap() = Some(client.clone());
+        joined.clone()
+    };
+    *connected = true;
+    events.send(Event::Connected).await?;
+
+    client.session(&connection.session).await?;
+    for group_name in joined {
+        client.send(&FromClient::Join { group_name }).await?;
+    }
+
+    forward_events(connection, events_from_server, events).await
+}
+
+/// 32 random hex digits. Each `RandomState` has keys drawn from the system's
+/// random number source, so even hashing nothing gives an unpredictable value.
+fn new_session_token() -> String {
+    (0..2)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect()
+}
+
+async fn forward_events(
+    connection: &Connection,
+    mut from_server: Events,
+    events: &channel::Sender<Event>,
+) -> ChatResult<()> {
+    while let Some(packet) = from_server.next().await {
+        let packet = packet?;
+        if let FromServer::JoinRefused { group_name, .. } = &packet {
+            connection.forget(group_name);
+        }
+        events.send(Event::Server(packet)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_joins_remembered_while_disconnected() {
+        let connection = Connection::new("localhost:0".to_string());
+        let join = |name: &str| FromClient::Join {
+            group_name: Arc::new(name.to_string()),
+        };
+
+        rt::block_on(async {
+            assert!(connection.send(&join("Dogs")).await.is_err());
+            assert!(connection.send(&join("Cats")).await.is_err());
+            // Already waiting to be rejoined, so there's nothing to send.
+            assert!(connection.send(&join("Dogs")).await.is_ok());
+        });
+        assert_eq!(
+            *connection.joined.lock().unwrap(),
+            vec![Arc::new("Dogs".to_string()), Arc::new("Cats".to_string())]
+        );
+
+        // Once refused, a group is forgotten, and can be asked for again.
+        connection.forget(&Arc::new("Dogs".to_string()));
+        assert_eq!(
+            *connection.joined.lock().unwrap(),
+            vec![Arc::new("Cats".to_string())]
+        );
+        rt::block_on(async {
+            assert!(connection.send(&join("Dogs")).await.is_err());
+        });
+        assert_eq!(connection.joined.lock().unwrap().len(), 2);
+    }
+}
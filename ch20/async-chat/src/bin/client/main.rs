@@ -0,0 +1,219 @@
+use async_channel as channel;
+use async_chat::utils::ChatResult;
+use async_chat::{rt, FromClient, FromServer};
+use ratatui::crossterm::event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::DefaultTerminal;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use crate::app::{Action, App};
+use crate::connection::Connection;
+
+mod app;
+mod connection;
+mod ui;
+
+/// Everything the main loop waits for.
+enum Event {
+    Terminal(event::Event),
+    Server(FromServer),
+    Connected,
+    Disconnected(String),
+    /// Something to tell the user, from a task running in the background.
+    Notice(String),
+}
+
+/// Parse a line (presumably read from the standard input) as a `Request`.
+fn parse_command(line: &str) -> Option<FromClient> {
+    let (command, rest) = get_next_token(line)?;
+    if command == "post" {
+        let (group, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+        Some(FromClient::Post {
+            group_name: Arc::new(group.to_string()),
+            message: Arc::new(message),
+        })
+    } else if command == "edit" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_token(rest)?;
+        Some(FromClient::Edit {
+            group_name: Arc::new(group.to_string()),
+            id: id.parse().ok()?,
+            message: Arc::new(rest.trim_start().to_string()),
+        })
+    } else if command == "delete" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::Delete {
+            group_name: Arc::new(group.to_string()),
+            id: id.parse().ok()?,
+        })
+    } else if command == "react" {
+        let (group, rest) = get_next_token(rest)?;
+        let (id, rest) = get_next_token(rest)?;
+        let (reaction, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::React {
+            group_name: Arc::new(group.to_string()),
+            id: id.parse().ok()?,
+            reaction: Arc::new(reaction.to_string()),
+        })
+    } else if command == "join" {
+        let (group, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+        Some(FromClient::Join {
+            group_name: Arc::new(group.to_string()),
+        })
+    } else {
+        None
+    }
+}
+
+/// Given a string `input`, return `Some((token, rest))`, where `token` is the
+/// first run of non-whitespace characters in `input`, and `rest` is the rest of
+/// the string. If the string contains no non-whitespace characters, return
+/// `None`.
+fn get_next_token(mut input: &str) -> Option<(&str, &str)> {
+    input = input.trim_start();
+
+    if input.is_empty() {
+        return None;
+    }
+
+    match input.find(char::is_whitespace) {
+        Some(space) => Some((&input[0..space], &input[space..])),
+        None => Some((input, "")),
+    }
+}
+
+/// Read keystrokes and resizes on a thread of their own, since crossterm's
+/// `read` blocks.
+fn read_terminal(events: channel::Sender<Event>) {
+    while let Ok(terminal_event) = event::read() {
+        if events
+            .send_blocking(Event::Terminal(terminal_event))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn handle_key(app: &mut App, key: KeyEvent) -> Action {
+    let control = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Esc => return Action::Quit,
+        KeyCode::Char('c') | KeyCode::Char('d') if control => return Action::Quit,
+        KeyCode::Char('n') if control => app.select_next(),
+        KeyCode::Char('p') if control => app.select_previous(),
+        KeyCode::Char(c) => app.type_char(c),
+        KeyCode::Backspace => app.backspace(),
+        KeyCode::Enter => return app.submit(),
+        KeyCode::Tab => app.complete(),
+        KeyCode::Up => app.history_previous(),
+        KeyCode::Down => app.history_next(),
+        KeyCode::PageUp => app.scroll_up(10),
+        KeyCode::PageDown => app.scroll_down(10),
+        _ => {}
+    }
+    Action::Nothing
+}
+
+async fn run(
+    terminal: &mut DefaultTerminal,
+    connection: &Arc<Connection>,
+    events: channel::Receiver<Event>,
+    notices: channel::Sender<Event>,
+) -> ChatResult<()> {
+    let mut app = App::new();
+    app.notice(app::USAGE.to_string());
+    app.notice("Anything else is posted to the selected group.".to_string());
+
+    loop {
+        terminal.draw(|frame| ui::draw(frame, &app))?;
+
+        let action = match events.recv().await? {
+            Event::Terminal(event::Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                handle_key(&mut app, key)
+            }
+            Event::Terminal(_) => Action::Nothing,
+            Event::Server(packet) => {
+                app.handle_packet(packet);
+                Action::Nothing
+            }
+            Event::Connected => {
+                app.notice("connected to server".to_string());
+                Action::Nothing
+            }
+            Event::Disconnected(reason) | Event::Notice(reason) => {
+                app.notice(reason);
+                Action::Nothing
+            }
+        };
+
+        match action {
+            Action::Nothing => {}
+            Action::Send(request) => {
+                if let Err(error) = connection.send(&request).await {
+                    app.notice(format!("error sending to server: {}", error));
+                }
+            }
+            Action::Upload { group_name, path } => {
+                // Reading and sending a file takes a while; keep the screen
+                // going meanwhile.
+                app.notice(format!("uploading {}", path.display()));
+                rt::spawn(upload(
+                    connection.clone(),
+                    group_name,
+                    path,
+                    notices.clone(),
+                ));
+            }
+            Action::Quit => return Ok(()),
+        }
+    }
+}
+
+/// Upload `path` to `group_name`, and say how it went.
+async fn upload(
+    connection: Arc<Connection>,
+    group_name: Arc<String>,
+    path: PathBuf,
+    notices: channel::Sender<Event>,
+) {
+    let notice = match connection.upload(&group_name, &path).await {
+        Ok(upload_id) => format!("sent {} as upload {}", path.display(), upload_id),
+        Err(error) => format!("can't upload {}: {}", path.display(), error),
+    };
+    let _ignored = notices.send(Event::Notice(notice)).await;
+}
+
+fn main() -> ChatResult<()> {
+    let address = std::env::args()
+        .nth(1)
+        .expect("Useage: client ADDRESS:PORT");
+
+    rt::block_on(async {
+        let connection = Arc::new(Connection::new(address));
+        let (events_sender, events) = channel::unbounded();
+        rt::spawn(connection::keep_connected(
+            connection.clone(),
+            events_sender.clone(),
+        ));
+
+        let mut terminal = ratatui::init();
+        let notices = events_sender.clone();
+        thread::spawn(move || read_terminal(events_sender));
+        let result = run(&mut terminal, &connection, events, notices).await;
+        ratatui::restore();
+        result
+    })
+}
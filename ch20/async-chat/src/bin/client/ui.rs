@@ -0,0 +1,152 @@
+//! Drawing the client's screen: a group list on the left, the selected
+//! group's messages on the right, and the input line at the bottom.
+
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame,
+};
+
+use crate::app::{App, Entry};
+
+pub const HELP: &str =
+    "Enter: send  Tab: complete  Up/Down: history  Ctrl-N/P: switch group  PgUp/PgDn: scroll  Esc: quit";
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [main, input, status] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [sidebar, messages] =
+        Layout::horizontal([Constraint::Length(24), Constraint::Min(10)]).areas(main);
+
+    draw_sidebar(frame, app, sidebar);
+    draw_messages(frame, app, messages);
+
+    let input_block = Block::default().borders(Borders::ALL).title("Input");
+    let (shown, cursor) = input_view(&app.input, input.width.saturating_sub(2) as usize);
+    frame.render_widget(Paragraph::new(shown).block(input_block), input);
+    frame.set_cursor_position((input.x + 1 + cursor as u16, input.y + 1));
+
+    let status_text = if app.status.is_empty() {
+        HELP
+    } else {
+        &app.status
+    };
+    frame.render_widget(Paragraph::new(status_text), status);
+}
+
+fn draw_sidebar(frame: &mut Frame, app: &App, area: Rect) {
+    let items: Vec<ListItem> = app
+        .panes
+        .iter()
+        .map(|pane| match pane.unread {
+            0 => ListItem::new(pane.name.as_str()),
+            n => ListItem::new(format!("{} ({})", pane.name, n))
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Groups"))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut state = ListState::default().with_selected(Some(app.selected));
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn draw_messages(frame: &mut Frame, app: &App, area: Rect) {
+    let pane = app.selected_pane();
+    let title = match pane.scroll {
+        0 => pane.name.to_string(),
+        n => format!("{} (scrolled back {})", pane.name, n),
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let inner = block.inner(area);
+
+    let lines: Vec<String> = pane.lines.iter().map(Entry::render).collect();
+    let rows = visible_rows(
+        &lines,
+        pane.scroll,
+        inner.width as usize,
+        inner.height as usize,
+    );
+    let lines: Vec<Line> = rows.into_iter().map(Line::from).collect();
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// The screen rows to show for `lines`, wrapped to `width` columns, when
+/// scrolled back `scroll` lines from the bottom of a view `height` rows tall.
+fn visible_rows(lines: &[String], scroll: usize, width: usize, height: usize) -> Vec<String> {
+    let end = lines.len().saturating_sub(scroll);
+    let mut rows = vec![];
+    // Wrap from the bottom up, and only as much as fits.
+    for line in lines[..end].iter().rev() {
+        let mut wrapped = wrap(line, width.max(1));
+        wrapped.reverse();
+        rows.extend(wrapped);
+        if rows.len() >= height {
+            break;
+        }
+    }
+    rows.truncate(height);
+    rows.reverse();
+    rows
+}
+
+/// The part of `input` to show in a box `width` columns wide, and the cursor's
+/// column within it. The cursor sits at the end of the input, so once the
+/// input is too long to fit, we scroll it left and show the end.
+fn input_view(input: &str, width: usize) -> (&str, usize) {
+    let width = width.max(1);
+    let len = input.chars().count();
+    if len < width {
+        return (input, len);
+    }
+    // Leave the last column for the cursor.
+    let skip = len - (width - 1);
+    let start = input
+        .char_indices()
+        .nth(skip)
+        .map_or(input.len(), |(i, _)| i);
+    (&input[start..], width - 1)
+}
+
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(width)
+        .map(|row| row.iter().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_visible_rows() {
+        let lines: Vec<String> = ["one", "twotwotwo", "three"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(visible_rows(&lines, 0, 4, 3), ["o", "thre", "e"]);
+        assert_eq!(visible_rows(&lines, 1, 4, 10), ["one", "twot", "wotw", "o"]);
+        assert_eq!(visible_rows(&lines, 3, 4, 10), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_input_view() {
+        assert_eq!(input_view("", 5), ("", 0));
+        assert_eq!(input_view("abcd", 5), ("abcd", 4));
+        assert_eq!(input_view("abcde", 5), ("bcde", 4));
+        assert_eq!(input_view("héllo wörld", 5), ("örld", 4));
+        let long = "x".repeat(70_000);
+        assert_eq!(input_view(&long, 80), (&long[..79], 79));
+    }
+}
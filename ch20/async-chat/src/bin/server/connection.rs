@@ -1,64 +0,0 @@
-use std::sync::Arc;
-
-use async_chat::{
-    utils::{self, ChatResult},
-    FromClient, FromServer,
-};
-use async_std::{
-    io::{BufReader, WriteExt},
-    net::TcpStream,
-    prelude::*,
-    sync::Mutex,
-};
-
-use crate::group_table::GroupTable;
-
-pub async fn serve(socket: TcpStream, groups: Arc<GroupTable>) -> ChatResult<()> {
-    let outbound = Arc::new(Outbound::new(socket.clone()));
-
-    let buffered = BufReader::new(socket);
-    let mut from_client = utils::receive_as_json(buffered);
-    while let Some(request_result) = from_client.next().await {
-        let request = request_result?;
-        let result = match request {
-            FromClient::Join { group_name } => {
-                let group = groups.get_or_create(group_name);
-                group.join(outbound.clone());
-                Ok(())
-            }
-            FromClient::Post {
-                group_name,
-                message,
-            } => match groups.get(&group_name) {
-                Some(group) => {
-                    group.post(message);
-                    Ok(())
-                }
-                None => Err(format!("Group {group_name} does not exist ")),
-            },
-        };
-
-        if let Err(message) = result {
-            let report = FromServer::Error(message);
-            outbound.send(report).await?;
-        }
-    }
-    Ok(())
-}
-
-pub struct Outbound(Mutex<TcpStream>);
-
-impl Outbound {
-    pub fn new(to_client: TcpStream) -> Self {
-        Outbound(Mutex::new(to_client))
-    }
-
-    pub async fn send(&self, packet: FromServer) -> ChatResult<()> {
-        // async_stdのMutexでないとasyncとMutexを併用するのに問題がある
-        let mut guard = self.0.lock().await;
-        // *guardでMutexの中のTcpStreamを得て、そのmut参照を渡す
-        utils::send_as_json(&mut *guard, &packet).await?;
-        guard.flush().await?;
-        Ok(())
-    }
-}
@@ -1,39 +1,34 @@
-use std::sync::Arc;
+use std::io::IsTerminal;
 
-use async_chat::utils::ChatResult;
-use async_std::prelude::*;
-
-use crate::connection::serve;
-
-mod connection;
-mod group;
-mod group_table;
+use async_chat::{
+    server::{config, shutdown, Config, Server},
+    utils::ChatResult,
+};
+use tracing_subscriber::EnvFilter;
 
 fn main() -> ChatResult<()> {
-    let address = std::env::args().nth(1).expect("Usage: server ADDRESS");
-
-    let chat_group_table = Arc::new(group_table::GroupTable::new());
-
-    async_std::task::block_on(async {
-        use async_std::{net, task};
-
-        let listener = net::TcpListener::bind(address).await?;
-
-        let mut new_connextions = listener.incoming();
-        while let Some(socket_result) = new_connextions.next().await {
-            let socket = socket_result?;
-            let groups = chat_group_table.clone();
-            task::spawn(async {
-                log_error(serve(socket, groups).await);
-            });
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{message}\n{}", config::USAGE);
+            std::process::exit(1);
         }
-
-        Ok(())
+    };
+
+    // Log to stderr as `key=value` fields; RUST_LOG overrides the level.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .init();
+
+    let (trigger, shutdown) = shutdown::channel();
+    shutdown::trigger_on_signals(trigger)?;
+
+    async_chat::rt::block_on(async {
+        let server = Server::bind(config).await?;
+        server.run(shutdown).await
     })
 }
-
-fn log_error(result: ChatResult<()>) {
-    if let Err(error) = result {
-        eprintln!("Error: {}", error);
-    }
-}
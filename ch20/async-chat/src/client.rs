@@ -0,0 +1,357 @@
+//! A client for the chat server, for bots and tests as well as our own
+//! terminal client.
+//!
+//! `ChatClient::connect` returns two halves: a `ChatClient` for sending
+//! requests, which can be cloned and shared between tasks, and an `Events`
+//! stream of what the server sends back. Heartbeats are answered while the
+//! stream is polled, so keep polling it, or the server will drop the
+//! connection as idle. `ChatClient::upload` also hears back through it, so
+//! an upload won't get going without it.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures_lite::{
+    io::{AsyncWriteExt, BufReader},
+    Stream, StreamExt,
+};
+use tokio::sync::{oneshot, Mutex};
+
+use crate::{
+    rt::{self, TcpStream},
+    utils::{self, ChatResult},
+    AttachmentId, FromClient, FromServer, MessageId, UploadId,
+};
+
+/// How much of a file goes in each `UploadChunk`: small enough to fit the
+/// server's default line length limit once base64 encoded.
+pub const UPLOAD_CHUNK_LEN: usize = 32 * 1024;
+
+#[derive(Clone)]
+pub struct ChatClient {
+    /// Our half of the connection. Whole packets must go out under the lock,
+    /// since clones of this client and `Events` all write to it.
+    to_server: Arc<Mutex<rt::WriteHalf>>,
+    next_upload_id: Arc<AtomicU64>,
+    /// Uploads waiting to hear whether the server will take them.
+    starting_uploads: Arc<StartingUploads>,
+}
+
+/// The server's answers to our `StartUpload`s, by upload id: `Ok` to go
+/// ahead, or the reason it refused.
+type UploadAnswer = oneshot::Sender<Result<(), String>>;
+
+#[derive(Default)]
+struct StartingUploads(std::sync::Mutex<HashMap<UploadId, UploadAnswer>>);
+
+impl StartingUploads {
+    /// Get ready for the server's answer to upload `upload_id`.
+    fn expect(&self, upload_id: UploadId) -> oneshot::Receiver<Result<(), String>> {
+        let (answer, answered) = oneshot::channel();
+        self.0.lock().unwrap().insert(upload_id, answer);
+        answered
+    }
+
+    fn forget(&self, upload_id: UploadId) {
+        self.0.lock().unwrap().remove(&upload_id);
+    }
+
+    /// Pass `packet` to the upload waiting for it, if it's an answer to a
+    /// `StartUpload` and someone is; otherwise hand it back.
+    fn answer(&self, packet: FromServer) -> Option<FromServer> {
+        let (upload_id, answer) = match &packet {
+            FromServer::UploadStarted { upload_id } => (*upload_id, Ok(())),
+            FromServer::UploadRefused { upload_id, reason } => (*upload_id, Err(reason.clone())),
+            _ => return Some(packet),
+        };
+        match self.0.lock().unwrap().remove(&upload_id) {
+            Some(waiting) => {
+                let _ignored = waiting.send(answer);
+                None
+            }
+            None => Some(packet),
+        }
+    }
+
+    /// Drop every waiting upload's sender, telling them no answer is coming.
+    fn abandon_all(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Lives as long as the `Events` stream. Once that's gone, nothing will
+/// answer the uploads still waiting, so drop their senders to tell them.
+struct AnswersGuard(Arc<StartingUploads>);
+
+impl Drop for AnswersGuard {
+    fn drop(&mut self) {
+        self.0.abandon_all();
+    }
+}
+
+/// Packets from the server, other than heartbeats. The stream ends when the
+/// server closes the connection.
+pub struct Events {
+    inner: Pin<Box<dyn Stream<Item = ChatResult<FromServer>> + Send>>,
+}
+
+impl ChatClient {
+    pub async fn connect(address: &str) -> ChatResult<(ChatClient, Events)> {
+        let socket = TcpStream::connect(address).await?;
+        socket.set_nodelay(true)?;
+        let (from_server, to_server) = socket.into_split();
+        let client = ChatClient {
+            to_server: Arc::new(Mutex::new(to_server)),
+            next_upload_id: Arc::new(AtomicU64::new(1)),
+            starting_uploads: Arc::default(),
+        };
+
+        // receive_as_jsonはストリームを返す。ストリームとは非同期のイテレータのようなもので
+        // 非同期に適した形で値の列を必要に応じて生成する
+        // futures-core
+        // pub trait Stream {
+        //     type Item;
+        //     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+        // }
+        // IteratorとFutureトレイトのハイブリッドのようなもの
+        // poll_nextを直接使うのではなく、filter,mapやnextメッソ度をつかってそれが返すFeatureにawaitすればよい
+        let replies = Box::pin(utils::receive_as_json(BufReader::new(from_server)));
+        let guard = AnswersGuard(client.starting_uploads.clone());
+        let inner = futures_lite::stream::unfold(
+            (replies, client.clone(), guard),
+            |(mut replies, client, guard)| async move {
+                loop {
+                    let reply = match replies.next().await? {
+                        Ok(FromServer::Ping) => match client.send(&FromClient::Pong).await {
+                            Ok(()) => continue,
+                            Err(error) => Err(error),
+                        },
+                        Ok(packet) => match client.starting_uploads.answer(packet) {
+                            Some(packet) => Ok(packet),
+                            None => continue,
+                        },
+                        other => other,
+                    };
+                    return Some((reply, (replies, client, guard)));
+                }
+            },
+        );
+
+        let events = Events {
+            inner: Box::pin(inner),
+        };
+        Ok((client, events))
+    }
+
+    pub async fn send(&self, request: &FromClient) -> ChatResult<()> {
+        let mut guard = self.to_server.lock().await;
+        utils::send_as_json(&mut *guard, request).await?;
+        guard.flush().await?;
+        Ok(())
+    }
+
+    pub async fn join(&self, group_name: &str) -> ChatResult<()> {
+        self.send(&FromClient::Join {
+            group_name: Arc::new(group_name.to_string()),
+        })
+        .await
+    }
+
+    /// Name our session, as `FromClient::Session` describes.
+    pub async fn session(&self, token: &str) -> ChatResult<()> {
+        self.send(&FromClient::Session {
+            token: Arc::new(token.to_string()),
+        })
+        .await
+    }
+
+    pub async fn post(&self, group_name: &str, message: &str) -> ChatResult<()> {
+        self.send(&FromClient::Post {
+            group_name: Arc::new(group_name.to_string()),
+            message: Arc::new(message.to_string()),
+        })
+        .await
+    }
+
+    pub async fn edit(&self, group_name: &str, id: MessageId, message: &str) -> ChatResult<()> {
+        self.send(&FromClient::Edit {
+            group_name: Arc::new(group_name.to_string()),
+            id,
+            message: Arc::new(message.to_string()),
+        })
+        .await
+    }
+
+    pub async fn delete(&self, group_name: &str, id: MessageId) -> ChatResult<()> {
+        self.send(&FromClient::Delete {
+            group_name: Arc::new(group_name.to_string()),
+            id,
+        })
+        .await
+    }
+
+    pub async fn react(&self, group_name: &str, id: MessageId, reaction: &str) -> ChatResult<()> {
+        self.send(&FromClient::React {
+            group_name: Arc::new(group_name.to_string()),
+            id,
+            reaction: Arc::new(reaction.to_string()),
+        })
+        .await
+    }
+
+    /// Upload `data` as an attachment to `group_name`. We wait for the server
+    /// to accept the upload before sending any of it, returning its reason if
+    /// it refuses; that answer comes through `Events`, so keep polling it
+    /// meanwhile. Once the upload is stored, the server sends
+    /// `FromServer::Uploaded` carrying the returned id.
+    pub async fn upload(
+        &self,
+        group_name: &str,
+        file_name: &str,
+        data: &[u8],
+    ) -> ChatResult<UploadId> {
+        let upload_id = self.next_upload_id.fetch_add(1, Ordering::Relaxed);
+        let answered = self.starting_uploads.expect(upload_id);
+        let start = FromClient::StartUpload {
+            upload_id,
+            group_name: Arc::new(group_name.to_string()),
+            file_name: Arc::new(file_name.to_string()),
+            size: data.len() as u64,
+        };
+        if let Err(error) = self.send(&start).await {
+            self.starting_uploads.forget(upload_id);
+            return Err(error);
+        }
+        match answered.await {
+            Ok(Ok(())) => {}
+            Ok(Err(reason)) => return Err(reason.into()),
+            Err(_) => return Err("connection closed before the upload started".into()),
+        }
+
+        // An empty file still takes one chunk, to finish the upload.
+        let mut offset = 0;
+        loop {
+            let end = (offset + UPLOAD_CHUNK_LEN).min(data.len());
+            self.send(&FromClient::UploadChunk {
+                upload_id,
+                offset: offset as u64,
+                data: data[offset..end].to_vec(),
+            })
+            .await?;
+            offset = end;
+            if offset == data.len() {
+                return Ok(upload_id);
+            }
+        }
+    }
+
+    /// Ask for an attachment's contents. Collect the `DownloadChunk`s that
+    /// follow with a `Download`.
+    pub async fn download(&self, attachment_id: AttachmentId) -> ChatResult<()> {
+        self.send(&FromClient::Download { attachment_id }).await
+    }
+}
+
+/// Reassembles an attachment from its `DownloadChunk`s.
+#[derive(Default)]
+pub struct Download {
+    data: Vec<u8>,
+}
+
+impl Download {
+    /// Add the next chunk, returning the whole attachment once it's complete.
+    pub fn add(&mut self, offset: u64, size: u64, chunk: &[u8]) -> ChatResult<Option<Vec<u8>>> {
+        if offset != self.data.len() as u64 || offset + chunk.len() as u64 > size {
+            return Err(format!("download chunk at offset {} out of place", offset).into());
+        }
+        self.data.extend_from_slice(chunk);
+        if self.data.len() as u64 == size {
+            return Ok(Some(std::mem::take(&mut self.data)));
+        }
+        Ok(None)
+    }
+}
+
+impl Stream for Events {
+    type Item = ChatResult<FromServer>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rt::TcpListener;
+
+    #[test]
+    fn test_chat_client() -> ChatResult<()> {
+        rt::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let address = listener.local_addr()?.to_string();
+
+            // A stand-in server that checks what a bot says and answers it.
+            let server = rt::spawn(async move {
+                let (socket, _) = listener.accept().await?;
+                let (reader, mut socket) = socket.into_split();
+                let requests = utils::receive_as_json(BufReader::new(reader));
+                futures_lite::pin!(requests);
+
+                let join: FromClient = requests.next().await.unwrap()?;
+                assert_eq!(
+                    join,
+                    FromClient::Join {
+                        group_name: Arc::new("Dogs".to_string())
+                    }
+                );
+                utils::send_as_json(&mut socket, &FromServer::Ping).await?;
+                let welcome = FromServer::Error("welcome".to_string());
+                utils::send_as_json(&mut socket, &welcome).await?;
+                assert_eq!(requests.next().await.unwrap()?, FromClient::Pong);
+
+                let post: FromClient = requests.next().await.unwrap()?;
+                if let FromClient::Post {
+                    group_name,
+                    message,
+                } = post
+                {
+                    let echo = FromServer::Message {
+                        group_name,
+                        id: 1,
+                        message,
+                    };
+                    utils::send_as_json(&mut socket, &echo).await?;
+                }
+                ChatResult::Ok(())
+            });
+
+            let (client, mut events) = ChatClient::connect(&address).await?;
+            client.join("Dogs").await?;
+            // The Ping is answered inside `next`, and never shows up here.
+            let welcome = events.next().await.unwrap()?;
+            assert_eq!(welcome, FromServer::Error("welcome".to_string()));
+
+            client.post("Dogs", "Samoyeds rock!").await?;
+            assert_eq!(
+                events.next().await.unwrap()?,
+                FromServer::Message {
+                    group_name: Arc::new("Dogs".to_string()),
+                    id: 1,
+                    message: Arc::new("Samoyeds rock!".to_string()),
+                }
+            );
+
+            server.await?;
+            assert!(events.next().await.is_none());
+            Ok(())
+        })
+    }
+}
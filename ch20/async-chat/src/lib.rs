@@ -1,26 +1,148 @@
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+pub mod client;
+pub mod rt;
+pub mod server;
 pub mod utils;
 
+pub use client::ChatClient;
+
+/// Identifies a message within its group. The server numbers each group's
+/// messages from 1 as they're posted.
+pub type MessageId = u64;
+
+/// The client's name for an upload in progress, unique among its own uploads.
+pub type UploadId = u64;
+
+/// The server's name for a stored attachment.
+pub type AttachmentId = u64;
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromClient {
     Join {
         group_name: Arc<String>,
     },
+    /// Name this connection's session with a hard-to-guess token of at least
+    /// 16 characters. Messages posted under a session can be edited and
+    /// deleted from any connection naming the same session, so a client that
+    /// reconnects with its token keeps its messages. Without a session, only
+    /// the connection that posted a message can change it.
+    Session {
+        token: Arc<String>,
+    },
     Post {
         group_name: Arc<String>,
         message: Arc<String>,
     },
+    /// Replace the text of a message we posted.
+    Edit {
+        group_name: Arc<String>,
+        id: MessageId,
+        message: Arc<String>,
+    },
+    /// Withdraw a message we posted.
+    Delete {
+        group_name: Arc<String>,
+        id: MessageId,
+    },
+    /// React to anyone's message, say with an emoji. Each reaction counts
+    /// once per connection, or per session.
+    React {
+        group_name: Arc<String>,
+        id: MessageId,
+        reaction: Arc<String>,
+    },
+    /// Begin uploading a file of `size` bytes to share with a group. Once the
+    /// server answers with `UploadStarted`, send its contents with
+    /// `UploadChunk`s; once they add up to `size`, the server stores the file
+    /// and announces it to the group.
+    StartUpload {
+        upload_id: UploadId,
+        group_name: Arc<String>,
+        file_name: Arc<String>,
+        size: u64,
+    },
+    /// The next piece of an upload. Chunks must arrive in order, and each must
+    /// fit within the server's line length limit once encoded.
+    UploadChunk {
+        upload_id: UploadId,
+        offset: u64,
+        #[serde(with = "utils::base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// Ask for an attachment's contents, which arrive as `DownloadChunk`s.
+    Download {
+        attachment_id: AttachmentId,
+    },
+    /// Reply to a `FromServer::Ping` heartbeat.
+    Pong,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum FromServer {
     Message {
         group_name: Arc<String>,
+        id: MessageId,
         message: Arc<String>,
     },
+    Edited {
+        group_name: Arc<String>,
+        id: MessageId,
+        message: Arc<String>,
+    },
+    Deleted {
+        group_name: Arc<String>,
+        id: MessageId,
+    },
+    Reacted {
+        group_name: Arc<String>,
+        id: MessageId,
+        reaction: Arc<String>,
+    },
+    /// A file shared with the group. `sha256` is the hex hash of its contents.
+    Attachment {
+        group_name: Arc<String>,
+        attachment_id: AttachmentId,
+        file_name: Arc<String>,
+        size: u64,
+        sha256: String,
+    },
+    /// To the uploader: the server will take this upload's chunks now.
+    UploadStarted {
+        upload_id: UploadId,
+    },
+    /// To the uploader: sent instead of `Error` when a `StartUpload` is
+    /// refused. Don't send its chunks.
+    UploadRefused {
+        upload_id: UploadId,
+        reason: String,
+    },
+    /// To the uploader: the server has stored this upload.
+    Uploaded {
+        upload_id: UploadId,
+        attachment_id: AttachmentId,
+    },
+    /// A piece of a requested attachment, `size` bytes in all.
+    DownloadChunk {
+        attachment_id: AttachmentId,
+        offset: u64,
+        size: u64,
+        #[serde(with = "utils::base64_bytes")]
+        data: Vec<u8>,
+    },
     Error(String),
+    /// Sent instead of `Error` when a `Join` is refused, so the client can
+    /// tell which group it isn't in.
+    JoinRefused {
+        group_name: Arc<String>,
+        reason: String,
+    },
+    /// Heartbeat; the client should answer with `FromClient::Pong`.
+    Ping,
+    /// The server is going away, and will close the connection once it has
+    /// flushed what it already queued.
+    Shutdown(String),
 }
 
 #[cfg(test)]
@@ -45,4 +167,50 @@ mod tests {
             from_client
         );
     }
+
+    #[test]
+    fn test_heartbeat_json() {
+        assert_eq!(
+            serde_json::to_string(&FromServer::Ping).unwrap(),
+            r#""Ping""#
+        );
+        assert_eq!(
+            serde_json::from_str::<FromClient>(r#""Pong""#).unwrap(),
+            FromClient::Pong
+        );
+        assert_eq!(
+            serde_json::to_string(&FromServer::Shutdown("bye".to_string())).unwrap(),
+            r#"{"Shutdown":"bye"}"#
+        );
+    }
+
+    #[test]
+    fn test_upload_chunk_json() {
+        let chunk = FromClient::UploadChunk {
+            upload_id: 1,
+            offset: 0,
+            data: b"hi!".to_vec(),
+        };
+        let json = serde_json::to_string(&chunk).unwrap();
+        assert_eq!(
+            json,
+            r#"{"UploadChunk":{"upload_id":1,"offset":0,"data":"aGkh"}}"#
+        );
+        assert_eq!(serde_json::from_str::<FromClient>(&json).unwrap(), chunk);
+    }
+
+    #[test]
+    fn test_edit_json() {
+        let edit = FromClient::Edit {
+            group_name: Arc::new("Dogs".to_string()),
+            id: 7,
+            message: Arc::new("Samoyeds rock!".to_string()),
+        };
+        let json = serde_json::to_string(&edit).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Edit":{"group_name":"Dogs","id":7,"message":"Samoyeds rock!"}}"#
+        );
+        assert_eq!(serde_json::from_str::<FromClient>(&json).unwrap(), edit);
+    }
 }
@@ -0,0 +1,110 @@
+//! The runtime interface, on async-std.
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_std::{net, task};
+
+use super::TimedOut;
+
+pub struct TcpListener(net::TcpListener);
+
+impl TcpListener {
+    pub async fn bind(address: &str) -> io::Result<TcpListener> {
+        Ok(TcpListener(net::TcpListener::bind(address).await?))
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (socket, peer) = self.0.accept().await?;
+        Ok((TcpStream(socket), peer))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
+
+pub struct TcpStream(net::TcpStream);
+
+/// async-std streams can be read and written through clones of themselves.
+pub type ReadHalf = net::TcpStream;
+pub type WriteHalf = net::TcpStream;
+
+impl TcpStream {
+    pub async fn connect(address: &str) -> io::Result<TcpStream> {
+        Ok(TcpStream(net::TcpStream::connect(address).await?))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.0.set_nodelay(nodelay)
+    }
+
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        (self.0.clone(), self.0)
+    }
+}
+
+/// Shut the connection down both ways once we're done writing. The reading
+/// clone is still open, so closing just the write half would leave the socket
+/// half-open until the reader noticed.
+pub async fn shutdown(stream: &mut WriteHalf) -> io::Result<()> {
+    stream.shutdown(std::net::Shutdown::Both)
+}
+
+pub struct JoinHandle<T>(task::JoinHandle<T>);
+
+impl<T> JoinHandle<T> {
+    /// Stop the task, waiting until it has been dropped.
+    pub async fn cancel(self) {
+        self.0.cancel().await;
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    JoinHandle(task::spawn(future))
+}
+
+/// Run `f` on a thread where it's fine to block.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    JoinHandle(task::spawn_blocking(f))
+}
+
+pub async fn sleep(duration: Duration) {
+    task::sleep(duration).await
+}
+
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, TimedOut> {
+    async_std::future::timeout(duration, future)
+        .await
+        .map_err(|_| TimedOut)
+}
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    task::block_on(future)
+}
@@ -0,0 +1,35 @@
+//! The async runtime the server and client run on, picked at build time:
+//! async-std by default, or tokio with `--no-default-features --features tokio`.
+//!
+//! Each backend offers the same small surface: TCP listeners and streams,
+//! spawning, sleeping, timeouts and `block_on`. Streams split into halves that
+//! implement the `futures-io` traits, so everything above this module does its
+//! I/O through `futures_lite`, and shares state through `async-channel` and
+//! `tokio::sync`, none of which care which runtime is driving them.
+
+use std::{error::Error, fmt};
+
+#[cfg(not(any(feature = "async-std", feature = "tokio")))]
+compile_error!("enable either the `async-std` or the `tokio` feature");
+
+#[cfg(not(feature = "tokio"))]
+mod async_std_backend;
+#[cfg(not(feature = "tokio"))]
+pub use async_std_backend::*;
+
+#[cfg(feature = "tokio")]
+mod tokio_backend;
+#[cfg(feature = "tokio")]
+pub use tokio_backend::*;
+
+/// The error `timeout` returns when the future didn't finish in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("timed out")
+    }
+}
+
+impl Error for TimedOut {}
@@ -0,0 +1,123 @@
+//! The runtime interface, on tokio.
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{net, task};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+use super::TimedOut;
+
+pub struct TcpListener(net::TcpListener);
+
+impl TcpListener {
+    pub async fn bind(address: &str) -> io::Result<TcpListener> {
+        Ok(TcpListener(net::TcpListener::bind(address).await?))
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (socket, peer) = self.0.accept().await?;
+        Ok((TcpStream(socket), peer))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
+
+pub struct TcpStream(net::TcpStream);
+
+/// tokio's own halves, adapted to the `futures-io` traits.
+pub type ReadHalf = Compat<net::tcp::OwnedReadHalf>;
+pub type WriteHalf = Compat<net::tcp::OwnedWriteHalf>;
+
+impl TcpStream {
+    pub async fn connect(address: &str) -> io::Result<TcpStream> {
+        Ok(TcpStream(net::TcpStream::connect(address).await?))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.0.set_nodelay(nodelay)
+    }
+
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        let (reader, writer) = self.0.into_split();
+        (reader.compat(), writer.compat_write())
+    }
+}
+
+/// Shut the connection down once we're done writing. tokio's write half can
+/// only close its own direction; the rest goes when the read half is dropped.
+pub async fn shutdown(stream: &mut WriteHalf) -> io::Result<()> {
+    futures_lite::AsyncWriteExt::close(stream).await
+}
+
+pub struct JoinHandle<T>(task::JoinHandle<T>);
+
+impl<T> JoinHandle<T> {
+    /// Stop the task, waiting until it has been dropped.
+    pub async fn cancel(self) {
+        self.0.abort();
+        let _ignored = self.0.await;
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        // Pass a task's panic on to whoever awaits it, as async-std does.
+        Pin::new(&mut self.0).poll(cx).map(|result| match result {
+            Ok(output) => output,
+            Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+            Err(error) => panic!("awaited a cancelled task: {error}"),
+        })
+    }
+}
+
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    JoinHandle(task::spawn(future))
+}
+
+/// Run `f` on a thread where it's fine to block.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    JoinHandle(task::spawn_blocking(f))
+}
+
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, TimedOut> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| TimedOut)
+}
+
+/// Run `future` to completion on a fresh multi-threaded runtime. Tasks it
+/// spawned that are still running when it finishes are dropped.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the tokio runtime")
+        .block_on(future)
+}
@@ -0,0 +1,93 @@
+//! A tiny HTTP endpoint reporting the server's counters as JSON:
+//!
+//! ```text
+//! $ curl http://localhost:8089/metrics
+//! {"connected_clients":2,"groups":1,"members":{"Dogs":2},...}
+//! ```
+
+use std::sync::Arc;
+
+use crate::{
+    rt::{self, TcpListener, TcpStream},
+    utils::ChatResult,
+};
+use futures_lite::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    FutureExt,
+};
+use tracing::{info, warn};
+
+use super::{shutdown::Shutdown, state::ServerState};
+
+/// Requests with a longer head than this are refused.
+const MAX_REQUEST_HEAD: u64 = 8 * 1024;
+
+/// Answer requests until `shutdown` is triggered.
+pub async fn serve_admin(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    mut shutdown: Shutdown,
+) -> ChatResult<()> {
+    info!(address = %listener.local_addr()?, "admin endpoint listening");
+    loop {
+        let accepted = async { Some(listener.accept().await) }
+            .race(async {
+                shutdown.wait().await;
+                None
+            })
+            .await;
+        let (socket, _) = match accepted {
+            Some(accept_result) => accept_result?,
+            None => return Ok(()),
+        };
+        let state = state.clone();
+        rt::spawn(async move {
+            // Don't let a client that never finishes its request hold on to
+            // a task and a socket forever.
+            let timeout = state.config.idle_timeout;
+            match rt::timeout(timeout, respond(socket, &state)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => warn!(%error, "admin request failed"),
+                Err(rt::TimedOut) => warn!(?timeout, "admin request timed out"),
+            }
+        });
+    }
+}
+
+async fn respond(socket: TcpStream, state: &ServerState) -> ChatResult<()> {
+    let (from_client, mut to_client) = socket.into_split();
+    let mut head = BufReader::new(from_client).take(MAX_REQUEST_HEAD);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).await?;
+    // We don't care about the headers, but read them so the client isn't cut
+    // off mid-request.
+    let mut header = String::new();
+    while head.read_line(&mut header).await? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", serde_json::to_string(&state.snapshot())?),
+        (Some("GET"), _) => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    );
+    to_client.write_all(response.as_bytes()).await?;
+    to_client.flush().await?;
+    Ok(())
+}
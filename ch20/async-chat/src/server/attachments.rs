@@ -0,0 +1,238 @@
+//! Files shared in groups: uploads in progress, and the store that keeps
+//! finished ones in memory until newer ones crowd them out.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use crate::{utils, AttachmentId, UploadId};
+
+use super::limits::{ByteBudget, ByteReservation};
+
+/// How many uploads one connection may have under way at once. The server
+/// as a whole is limited by the bytes they declare, in `ByteBudget`.
+const MAX_PENDING_UPLOADS: usize = 4;
+
+pub struct Attachment {
+    /// The group it was shared with. Only that group's members may download
+    /// it.
+    pub group_name: Arc<String>,
+    pub file_name: Arc<String>,
+    pub sha256: String,
+    pub data: Vec<u8>,
+}
+
+pub struct AttachmentStore {
+    /// The most bytes of attachments we'll keep at once.
+    capacity: usize,
+    stored: Mutex<Stored>,
+}
+
+struct Stored {
+    next_id: AttachmentId,
+    /// Oldest first, since ids only increase.
+    files: BTreeMap<AttachmentId, Arc<Attachment>>,
+    total_len: usize,
+}
+
+impl AttachmentStore {
+    pub fn new(capacity: usize) -> Self {
+        AttachmentStore {
+            capacity,
+            stored: Mutex::new(Stored {
+                next_id: 1,
+                files: BTreeMap::new(),
+                total_len: 0,
+            }),
+        }
+    }
+
+    /// Store `data`, shared with `group_name`, dropping the oldest attachments
+    /// if need be to make room.
+    pub fn insert(
+        &self,
+        group_name: Arc<String>,
+        file_name: Arc<String>,
+        data: Vec<u8>,
+    ) -> (AttachmentId, Arc<Attachment>) {
+        let attachment = Arc::new(Attachment {
+            group_name,
+            file_name,
+            sha256: utils::sha256_hex(&data),
+            data,
+        });
+
+        let mut stored = self.stored.lock().unwrap();
+        while stored.total_len + attachment.data.len() > self.capacity {
+            match stored.files.pop_first() {
+                Some((_id, oldest)) => stored.total_len -= oldest.data.len(),
+                None => break,
+            }
+        }
+        let id = stored.next_id;
+        stored.next_id += 1;
+        stored.total_len += attachment.data.len();
+        stored.files.insert(id, attachment.clone());
+        (id, attachment)
+    }
+
+    pub fn get(&self, id: AttachmentId) -> Option<Arc<Attachment>> {
+        self.stored.lock().unwrap().files.get(&id).cloned()
+    }
+}
+
+/// An upload whose chunks are still arriving.
+pub struct Upload {
+    pub group_name: Arc<String>,
+    pub file_name: Arc<String>,
+    size: u64,
+    pub data: Vec<u8>,
+    /// The upload's share of the server's upload buffer, held until it's
+    /// finished or abandoned.
+    _reservation: ByteReservation,
+}
+
+/// One connection's uploads in progress.
+#[derive(Default)]
+pub struct Uploads {
+    pending: HashMap<UploadId, Upload>,
+}
+
+impl Uploads {
+    pub fn start(
+        &mut self,
+        upload_id: UploadId,
+        group_name: Arc<String>,
+        file_name: Arc<String>,
+        size: u64,
+        max_size: usize,
+        buffer: &ByteBudget,
+    ) -> Result<(), String> {
+        if size > max_size as u64 {
+            return Err(format!(
+                "{file_name} is {size} bytes; attachments are limited to {max_size}"
+            ));
+        }
+        if self.pending.contains_key(&upload_id) {
+            return Err(format!("Upload {upload_id} is already under way"));
+        }
+        if self.pending.len() >= MAX_PENDING_UPLOADS {
+            return Err(format!(
+                "Only {MAX_PENDING_UPLOADS} uploads may be under way at once"
+            ));
+        }
+        let reservation = buffer
+            .try_reserve(size as usize)
+            .ok_or("Too many uploads under way on the server, try again later")?;
+        self.pending.insert(
+            upload_id,
+            Upload {
+                group_name,
+                file_name,
+                size,
+                data: vec![],
+                _reservation: reservation,
+            },
+        );
+        Ok(())
+    }
+
+    /// Add a chunk to an upload, returning the whole upload once it's complete.
+    /// A chunk at the wrong offset is refused, but the upload can carry on from
+    /// the right one; a chunk that overruns the declared size cancels it.
+    pub fn add_chunk(
+        &mut self,
+        upload_id: UploadId,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Option<Upload>, String> {
+        let upload = self
+            .pending
+            .get_mut(&upload_id)
+            .ok_or_else(|| format!("No upload {upload_id} under way"))?;
+        let received = upload.data.len() as u64;
+        if offset != received {
+            return Err(format!(
+                "Upload {upload_id}: expected a chunk at offset {received}, not {offset}"
+            ));
+        }
+        if received + data.len() as u64 > upload.size {
+            self.pending.remove(&upload_id);
+            return Err(format!(
+                "Upload {upload_id} overran its declared size; cancelled"
+            ));
+        }
+
+        upload.data.extend_from_slice(data);
+        if upload.data.len() as u64 == upload.size {
+            return Ok(self.pending.remove(&upload_id));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Arc<String> {
+        Arc::new(s.to_string())
+    }
+
+    #[test]
+    fn test_uploads() {
+        let mut uploads = Uploads::default();
+        let buffer = ByteBudget::new(1000);
+        assert!(uploads
+            .start(1, name("Dogs"), name("big"), 101, 100, &buffer)
+            .is_err());
+        uploads
+            .start(1, name("Dogs"), name("dog.txt"), 6, 100, &buffer)
+            .unwrap();
+        assert!(uploads
+            .start(1, name("Dogs"), name("again"), 6, 100, &buffer)
+            .is_err());
+
+        assert!(uploads.add_chunk(1, 0, b"woo").unwrap().is_none());
+        assert!(uploads.add_chunk(1, 0, b"woo").is_err());
+        let done = uploads.add_chunk(1, 3, b"f!!").unwrap().unwrap();
+        assert_eq!(done.data, b"woof!!");
+        assert!(uploads.add_chunk(1, 6, b"").is_err());
+
+        uploads
+            .start(2, name("Dogs"), name("short"), 2, 100, &buffer)
+            .unwrap();
+        assert!(uploads.add_chunk(2, 0, b"too long").is_err());
+        assert!(uploads.add_chunk(2, 0, b"ok").is_err());
+
+        // Uploads in progress are held to the server's buffer, and give their
+        // share back once done.
+        let small = ByteBudget::new(10);
+        uploads
+            .start(3, name("Dogs"), name("six"), 6, 100, &small)
+            .unwrap();
+        assert!(uploads
+            .start(4, name("Dogs"), name("five"), 5, 100, &small)
+            .is_err());
+        assert!(uploads.add_chunk(3, 0, b"sixsix").unwrap().is_some());
+        uploads
+            .start(4, name("Dogs"), name("five"), 5, 100, &small)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_store_evicts_oldest() {
+        let store = AttachmentStore::new(10);
+        let (first, _) = store.insert(name("Dogs"), name("a"), vec![0; 4]);
+        let (second, _) = store.insert(name("Dogs"), name("b"), vec![0; 4]);
+        let (third, attachment) = store.insert(name("Dogs"), name("c"), b"abc".to_vec());
+        assert!(store.get(first).is_none());
+        assert!(store.get(second).is_some());
+        assert_eq!(store.get(third).unwrap().data, b"abc");
+        assert_eq!(
+            attachment.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Server settings, taken from the command line.
+
+use std::{str::FromStr, time::Duration};
+
+use super::limits::Rate;
+
+pub const USAGE: &str = "Usage: server ADDRESS \
+    [--heartbeat SECS] [--idle-timeout SECS] [--drain-timeout SECS] \
+    [--max-line-len BYTES] [--max-connections N] \
+    [--conn-rate PER_SEC,BURST] [--ip-rate PER_SEC,BURST] [--group-grace SECS] \
+    [--max-attachment BYTES] [--attachment-store BYTES] [--upload-buffer BYTES] \
+    [--admin ADDRESS] [--node-id NAME] [--federation ADDRESS] [--peer ADDRESS]...";
+
+pub struct Config {
+    pub address: String,
+    /// How long a connection may stay silent before we send it a `Ping`.
+    pub heartbeat_interval: Duration,
+    /// How long a connection may stay silent, pongs included, before we drop it,
+    /// and how long it may take to accept a write.
+    pub idle_timeout: Duration,
+    /// How long shutdown waits for connections to flush their outbound queues.
+    pub drain_timeout: Duration,
+    /// The longest request line we'll read before dropping the connection.
+    pub max_line_len: usize,
+    /// Connections beyond this many are turned away with an error.
+    pub max_connections: usize,
+    /// How fast a single connection may send requests other than heartbeats.
+    /// Upload chunks beyond the rate are held back rather than refused.
+    pub connection_rate: Rate,
+    /// How fast all the connections from one IP address may send them, together.
+    pub ip_rate: Rate,
+    /// How long a group may sit with no members before we forget it.
+    pub group_grace: Duration,
+    /// The largest attachment we'll accept.
+    pub max_attachment_size: usize,
+    /// How many bytes of attachments we keep; the oldest go first.
+    pub attachment_store_size: usize,
+    /// How many bytes of uploads may be under way at once, across all
+    /// connections. Each upload counts its declared size from the start.
+    pub upload_buffer_size: usize,
+    /// Where to serve the admin endpoint, if anywhere.
+    pub admin_address: Option<String>,
+    /// This node's name among its peers. Defaults to `address`.
+    pub node_id: Option<String>,
+    /// Where to accept links from peer nodes, if anywhere.
+    pub federation_address: Option<String>,
+    /// The federation addresses of the other nodes, which we'll link to.
+    pub peers: Vec<String>,
+}
+
+impl Config {
+    /// Parse the server's arguments, not including the program name.
+    pub fn from_args<I>(args: I) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let mut address = None;
+        let mut config = Config {
+            address: String::new(),
+            heartbeat_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            drain_timeout: Duration::from_secs(5),
+            max_line_len: 64 * 1024,
+            max_connections: 1024,
+            connection_rate: Rate {
+                per_second: 5.0,
+                burst: 20.0,
+            },
+            ip_rate: Rate {
+                per_second: 20.0,
+                burst: 100.0,
+            },
+            group_grace: Duration::from_secs(60),
+            max_attachment_size: 8 * 1024 * 1024,
+            attachment_store_size: 256 * 1024 * 1024,
+            upload_buffer_size: 64 * 1024 * 1024,
+            admin_address: None,
+            node_id: None,
+            federation_address: None,
+            peers: vec![],
+        };
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                if address.is_some() {
+                    return Err(format!("unexpected argument {arg:?}"));
+                }
+                address = Some(arg);
+                continue;
+            }
+
+            let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+            let bad_value = || format!("bad value for {arg}: {value:?}");
+            match arg.as_str() {
+                "--heartbeat" => {
+                    config.heartbeat_interval = parse_seconds(&value).ok_or_else(bad_value)?
+                }
+                "--idle-timeout" => {
+                    config.idle_timeout = parse_seconds(&value).ok_or_else(bad_value)?
+                }
+                "--drain-timeout" => {
+                    config.drain_timeout = parse_seconds(&value).ok_or_else(bad_value)?
+                }
+                "--max-line-len" => {
+                    config.max_line_len = parse_positive(&value).ok_or_else(bad_value)?
+                }
+                "--max-connections" => {
+                    config.max_connections = parse_positive(&value).ok_or_else(bad_value)?
+                }
+                "--conn-rate" => {
+                    config.connection_rate = parse_rate(&value).ok_or_else(bad_value)?
+                }
+                "--ip-rate" => config.ip_rate = parse_rate(&value).ok_or_else(bad_value)?,
+                "--group-grace" => {
+                    config.group_grace = parse_seconds(&value).ok_or_else(bad_value)?
+                }
+                "--max-attachment" => {
+                    config.max_attachment_size = parse_positive(&value).ok_or_else(bad_value)?
+                }
+                "--attachment-store" => {
+                    config.attachment_store_size = parse_positive(&value).ok_or_else(bad_value)?
+                }
+                "--upload-buffer" => {
+                    config.upload_buffer_size = parse_positive(&value).ok_or_else(bad_value)?
+                }
+                "--admin" => config.admin_address = Some(value),
+                "--node-id" => config.node_id = Some(value),
+                "--federation" => config.federation_address = Some(value),
+                "--peer" => config.peers.push(value),
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+
+        config.address = address.ok_or("missing ADDRESS")?;
+        if config.idle_timeout < config.heartbeat_interval {
+            return Err("--idle-timeout must be at least as long as --heartbeat".to_string());
+        }
+        if config.attachment_store_size < config.max_attachment_size {
+            return Err("--attachment-store must hold at least one --max-attachment".to_string());
+        }
+        if config.upload_buffer_size < config.max_attachment_size {
+            return Err("--upload-buffer must hold at least one --max-attachment".to_string());
+        }
+        Ok(config)
+    }
+}
+
+fn parse_positive<T: FromStr + PartialOrd + Default>(s: &str) -> Option<T> {
+    s.parse::<T>().ok().filter(|n| *n > T::default())
+}
+
+fn parse_seconds(s: &str) -> Option<Duration> {
+    parse_positive::<f64>(s)
+        .filter(|secs| secs.is_finite())
+        .map(Duration::from_secs_f64)
+}
+
+/// Parse a rate written as `PER_SEC,BURST`, like `5,20`.
+fn parse_rate(s: &str) -> Option<Rate> {
+    let (per_second, burst) = s.split_once(',')?;
+    let rate = Rate {
+        per_second: parse_positive(per_second)?,
+        burst: parse_positive(burst)?,
+    };
+    // A bucket that can't hold a whole token would never let anything through.
+    (rate.burst >= 1.0 && rate.per_second.is_finite()).then_some(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = parse(&[
+            "localhost:8088",
+            "--heartbeat",
+            "1",
+            "--idle-timeout",
+            "2.5",
+            "--ip-rate",
+            "0.5,3",
+            "--peer",
+            "localhost:9001",
+            "--peer",
+            "localhost:9002",
+        ])
+        .unwrap();
+        assert_eq!(config.address, "localhost:8088");
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(1));
+        assert_eq!(config.idle_timeout, Duration::from_millis(2500));
+        assert_eq!(config.drain_timeout, Duration::from_secs(5));
+        assert_eq!(
+            config.ip_rate,
+            Rate {
+                per_second: 0.5,
+                burst: 3.0
+            }
+        );
+        assert_eq!(config.peers, vec!["localhost:9001", "localhost:9002"]);
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["localhost:8088", "--heartbeat"]).is_err());
+        assert!(parse(&["localhost:8088", "--heartbeat", "-1"]).is_err());
+        assert!(parse(&["localhost:8088", "--bogus", "1"]).is_err());
+        assert!(parse(&["localhost:8088", "--idle-timeout", "10"]).is_err());
+        assert!(parse(&["localhost:8088", "--max-connections", "0"]).is_err());
+        assert!(parse(&["localhost:8088", "--conn-rate", "5"]).is_err());
+        assert!(parse(&["localhost:8088", "--conn-rate", "5,0.5"]).is_err());
+        assert!(parse(&["localhost:8088", "--attachment-store", "1000"]).is_err());
+        assert!(parse(&["localhost:8088", "--upload-buffer", "1000"]).is_err());
+    }
+}
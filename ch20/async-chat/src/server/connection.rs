@@ -0,0 +1,475 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    rt::{self, TcpStream},
+    utils::{self, ChatResult},
+    AttachmentId, FromClient, FromServer,
+};
+use futures_lite::{
+    io::{AsyncWriteExt, BufReader},
+    FutureExt, StreamExt,
+};
+
+use tracing::{info, warn};
+
+use super::{
+    attachments::{Attachment, Uploads},
+    group::Group,
+    limits::{IpLimits, TokenBucket},
+    shutdown::Shutdown,
+    state::ServerState,
+};
+
+/// Tells a server's connections apart.
+pub type ConnectionId = u64;
+
+/// Who a request comes from, for checking who posted what: the connection,
+/// or, once the client has named one with `FromClient::Session`, its session,
+/// which carries over to later connections that name the same session.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Author {
+    Connection(ConnectionId),
+    Session(Arc<String>),
+}
+
+/// Session tokens are all that stands between one user and another's
+/// messages, so they had better not be guessable.
+const MIN_SESSION_TOKEN_LEN: usize = 16;
+
+/// What woke up the request loop in `serve`.
+enum Event {
+    Request(Option<ChatResult<FromClient>>),
+    Heartbeat,
+    Shutdown,
+}
+
+pub async fn serve(
+    socket: TcpStream,
+    state: Arc<ServerState>,
+    shutdown: Shutdown,
+) -> ChatResult<()> {
+    let peer_ip = socket.peer_addr()?.ip();
+    let id = state.new_connection_id();
+    let (from_client, to_client) = socket.into_split();
+    let (outbound, writer) = Outbound::new(to_client, state.config.idle_timeout);
+    let outbound = Arc::new(outbound);
+
+    let limits = Limits {
+        connection: TokenBucket::new(state.config.connection_rate),
+        ip: &state.ip_limits,
+        peer_ip,
+    };
+    let mut subscriptions = vec![];
+    let result = handle_requests(
+        from_client,
+        id,
+        &state,
+        limits,
+        &outbound,
+        &mut subscriptions,
+        shutdown,
+    )
+    .await;
+
+    // Leave our groups, so their member counts drop now rather than whenever
+    // someone next posts, then let the writer flush whatever is still queued.
+    for subscription in subscriptions {
+        subscription.cancel().await;
+    }
+    outbound.close();
+    let written = writer.await;
+    result.and(written)
+}
+
+/// The rate limits a connection's `Join` and `Post` requests must pass.
+struct Limits<'a> {
+    connection: TokenBucket,
+    ip: &'a IpLimits,
+    peer_ip: IpAddr,
+}
+
+impl Limits<'_> {
+    /// Take a token from both buckets, or from neither if either is empty:
+    /// a refused request shouldn't use up the connection's budget.
+    fn check(&mut self) -> Result<(), String> {
+        if !self.connection.has_token() {
+            return Err("Rate limit exceeded for this connection, slow down".to_string());
+        }
+        if !self.ip.try_take(self.peer_ip) {
+            return Err(format!(
+                "Rate limit exceeded for {}, slow down",
+                self.peer_ip
+            ));
+        }
+        // Only this connection takes from its own bucket, so the token we
+        // saw is still there.
+        self.connection.try_take();
+        Ok(())
+    }
+
+    /// Wait until `check` passes.
+    async fn wait(&mut self) {
+        while self.check().is_err() {
+            rt::sleep(THROTTLE_POLL).await;
+        }
+    }
+}
+
+/// How often a throttled upload chunk looks for tokens again.
+const THROTTLE_POLL: Duration = Duration::from_millis(50);
+
+async fn handle_requests(
+    from_client: rt::ReadHalf,
+    id: ConnectionId,
+    state: &ServerState,
+    mut limits: Limits<'_>,
+    outbound: &Arc<Outbound>,
+    subscriptions: &mut Vec<rt::JoinHandle<()>>,
+    mut shutdown: Shutdown,
+) -> ChatResult<()> {
+    let config = &state.config;
+    let buffered = BufReader::new(from_client);
+    let from_client = utils::receive_as_json_limited(buffered, config.max_line_len);
+    futures_lite::pin!(from_client);
+    let mut last_heard = Instant::now();
+    let mut uploads = Uploads::default();
+    let mut author = Author::Connection(id);
+    // The groups this connection has joined, whose attachments it may fetch.
+    let mut joined = HashSet::new();
+
+    loop {
+        // Dropping the losing `next()` future is fine: the stream keeps any
+        // partially read line in itself.
+        let event = async { Event::Request(from_client.next().await) }
+            .race(async {
+                rt::sleep(config.heartbeat_interval).await;
+                Event::Heartbeat
+            })
+            .race(async {
+                shutdown.wait().await;
+                Event::Shutdown
+            })
+            .await;
+
+        let request = match event {
+            Event::Request(Some(Ok(request))) => request,
+            Event::Request(Some(Err(error))) => {
+                // Tell the client why we're hanging up, if it's still listening.
+                let _ignored = outbound.send(FromServer::Error(error.to_string())).await;
+                return Err(error);
+            }
+            Event::Request(None) => return Ok(()),
+            // A dead peer stops taking packets, so its queue may well be full.
+            // Don't wait for room: that could take until the kernel gives up
+            // on the socket, minutes from now.
+            Event::Heartbeat if last_heard.elapsed() >= config.idle_timeout => {
+                info!("idle timeout");
+                let report = FromServer::Error("Idle timeout, closing connection".to_string());
+                outbound.try_send(report);
+                return Ok(());
+            }
+            Event::Heartbeat => {
+                // If the queue is full, the client has a ping's worth of
+                // packets to answer already.
+                outbound.try_send(FromServer::Ping);
+                continue;
+            }
+            Event::Shutdown => {
+                let notice = FromServer::Shutdown("Server is shutting down".to_string());
+                return outbound.send(notice).await;
+            }
+        };
+        last_heard = Instant::now();
+
+        let admitted = match request {
+            FromClient::Pong => Ok(()),
+            // Refusing a chunk would spoil its upload, so hold it until the
+            // limits allow it instead. We read nothing else from the client
+            // meanwhile, which slows it down to match.
+            FromClient::UploadChunk { .. } => {
+                let admitted = async {
+                    limits.wait().await;
+                    true
+                }
+                .race(async {
+                    shutdown.wait().await;
+                    false
+                })
+                .await;
+                if !admitted {
+                    let notice = FromServer::Shutdown("Server is shutting down".to_string());
+                    return outbound.send(notice).await;
+                }
+                Ok(())
+            }
+            _ => limits.check(),
+        };
+
+        // Refusals of joins and uploads say which one they refuse, so the
+        // client can tell which of its requests failed.
+        let refused_join = match &request {
+            FromClient::Join { group_name } => Some(group_name.clone()),
+            _ => None,
+        };
+        let refused_upload = match &request {
+            FromClient::StartUpload { upload_id, .. } => Some(*upload_id),
+            _ => None,
+        };
+        let mut reply = None;
+        let mut download = None;
+        let result = admitted.and_then(|()| match request {
+            FromClient::Join { group_name } => {
+                info!(group = %group_name, "joined");
+                joined.insert(group_name.clone());
+                subscriptions.push(state.groups.join(group_name, outbound.clone()));
+                Ok(())
+            }
+            FromClient::Post {
+                group_name,
+                message,
+            } => {
+                let group = find_group(state, &group_name)?;
+                let message_id = group.post(author.clone(), message.clone());
+                info!(group = %group_name, id = message_id, bytes = message.len(), "posted");
+                Ok(())
+            }
+            FromClient::Edit {
+                group_name,
+                id: message_id,
+                message,
+            } => {
+                find_group(state, &group_name)?.edit(&author, message_id, message)?;
+                info!(group = %group_name, id = message_id, "edited");
+                Ok(())
+            }
+            FromClient::Delete {
+                group_name,
+                id: message_id,
+            } => {
+                find_group(state, &group_name)?.delete(&author, message_id)?;
+                info!(group = %group_name, id = message_id, "deleted");
+                Ok(())
+            }
+            FromClient::React {
+                group_name,
+                id: message_id,
+                reaction,
+            } => find_group(state, &group_name)?.react(author.clone(), message_id, reaction),
+            FromClient::Session { token } => match author {
+                Author::Session(_) => Err("This connection already has a session".to_string()),
+                _ if token.chars().count() < MIN_SESSION_TOKEN_LEN => Err(format!(
+                    "Session token too short, use at least {MIN_SESSION_TOKEN_LEN} characters"
+                )),
+                _ => {
+                    author = Author::Session(token);
+                    Ok(())
+                }
+            },
+            FromClient::StartUpload {
+                upload_id,
+                group_name,
+                file_name,
+                size,
+            } => {
+                find_group(state, &group_name)?;
+                uploads.start(
+                    upload_id,
+                    group_name,
+                    file_name,
+                    size,
+                    config.max_attachment_size,
+                    &state.upload_buffer,
+                )?;
+                reply = Some(FromServer::UploadStarted { upload_id });
+                Ok(())
+            }
+            FromClient::UploadChunk {
+                upload_id,
+                offset,
+                data,
+            } => {
+                let upload = match uploads.add_chunk(upload_id, offset, &data)? {
+                    Some(upload) => upload,
+                    None => return Ok(()),
+                };
+                let group = find_group(state, &upload.group_name)?;
+                let (attachment_id, attachment) = state.attachments.insert(
+                    upload.group_name.clone(),
+                    upload.file_name,
+                    upload.data,
+                );
+                info!(
+                    group = %upload.group_name,
+                    attachment_id,
+                    bytes = attachment.data.len(),
+                    "uploaded"
+                );
+                group.announce_attachment(attachment_id, &attachment);
+                reply = Some(FromServer::Uploaded {
+                    upload_id,
+                    attachment_id,
+                });
+                Ok(())
+            }
+            FromClient::Download { attachment_id } => {
+                // Ids are handed out in order, so anyone could count through
+                // them; only the group's members may fetch its files. Don't
+                // say whether the attachment exists to those who may not.
+                let attachment = state
+                    .attachments
+                    .get(attachment_id)
+                    .filter(|attachment| joined.contains(&attachment.group_name))
+                    .ok_or_else(|| format!("No attachment {attachment_id} in your groups"))?;
+                download = Some((attachment_id, attachment));
+                Ok(())
+            }
+            FromClient::Pong => Ok(()),
+        });
+
+        if let Some(reply) = reply {
+            outbound.send(reply).await?;
+        }
+        if let Some((attachment_id, attachment)) = download {
+            send_attachment(outbound, attachment_id, &attachment).await?;
+        }
+        if let Err(message) = result {
+            warn!(error = %message, "request refused");
+            let report = match (refused_join, refused_upload) {
+                (Some(group_name), _) => FromServer::JoinRefused {
+                    group_name,
+                    reason: message,
+                },
+                (_, Some(upload_id)) => FromServer::UploadRefused {
+                    upload_id,
+                    reason: message,
+                },
+                _ => FromServer::Error(message),
+            };
+            outbound.send(report).await?;
+        }
+    }
+}
+
+/// How much of an attachment goes in each `DownloadChunk`. Base64 makes it a
+/// third bigger on the wire.
+const DOWNLOAD_CHUNK_LEN: usize = 32 * 1024;
+
+async fn send_attachment(
+    outbound: &Outbound,
+    attachment_id: AttachmentId,
+    attachment: &Attachment,
+) -> ChatResult<()> {
+    let data = &attachment.data;
+    let mut offset = 0;
+    // Even an empty attachment gets one chunk, so the client hears back.
+    loop {
+        let end = (offset + DOWNLOAD_CHUNK_LEN).min(data.len());
+        let packet = FromServer::DownloadChunk {
+            attachment_id,
+            offset: offset as u64,
+            size: data.len() as u64,
+            data: data[offset..end].to_vec(),
+        };
+        outbound.send(packet).await?;
+        offset = end;
+        if offset == data.len() {
+            return Ok(());
+        }
+    }
+}
+
+fn find_group(state: &ServerState, group_name: &String) -> Result<Arc<Group>, String> {
+    state
+        .groups
+        .get(group_name)
+        .ok_or_else(|| format!("Group {group_name} does not exist "))
+}
+
+/// Packets waiting to be written to a client. A full queue makes senders wait,
+/// so a slow client holds back only the tasks sending to it.
+const OUTBOUND_QUEUE_LEN: usize = 1000;
+
+pub struct Outbound(async_channel::Sender<FromServer>);
+
+impl Outbound {
+    /// Start a task writing packets to `to_client`, returning the queue that
+    /// feeds it and the task's handle. The task finishes once the queue is
+    /// closed and drained, or writing fails, or a write takes longer than
+    /// `write_timeout`.
+    pub fn new(
+        to_client: rt::WriteHalf,
+        write_timeout: Duration,
+    ) -> (Self, rt::JoinHandle<ChatResult<()>>) {
+        let (sender, receiver) = async_channel::bounded(OUTBOUND_QUEUE_LEN);
+        let writer = rt::spawn(write_packets(to_client, receiver, write_timeout));
+        (Outbound(sender), writer)
+    }
+
+    pub async fn send(&self, packet: FromServer) -> ChatResult<()> {
+        self.0.send(packet).await?;
+        Ok(())
+    }
+
+    /// Queue `packet` if there's room, and drop it if not.
+    pub fn try_send(&self, packet: FromServer) {
+        let _ignored = self.0.try_send(packet);
+    }
+
+    /// Refuse any further packets. Those already queued are still written.
+    pub fn close(&self) {
+        self.0.close();
+    }
+}
+
+/// A client that takes longer than `timeout` to accept a write has stopped
+/// reading, or is gone without the kernel having noticed yet; either way we
+/// give up on it, which closes the queue and fails whoever is sending to it.
+async fn write_packets(
+    mut to_client: rt::WriteHalf,
+    packets: async_channel::Receiver<FromServer>,
+    timeout: Duration,
+) -> ChatResult<()> {
+    while let Ok(packet) = packets.recv().await {
+        rt::timeout(timeout, utils::send_as_json(&mut to_client, &packet)).await??;
+        // Only flush once we've caught up, so a burst goes out together.
+        if packets.is_empty() {
+            rt::timeout(timeout, to_client.flush()).await??;
+        }
+    }
+    rt::timeout(timeout, to_client.flush()).await??;
+    // The client may well have hung up already.
+    let _ignored = rt::shutdown(&mut to_client).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::limits::Rate;
+
+    #[test]
+    fn test_refused_requests_cost_nothing() {
+        let rate = |burst| Rate {
+            per_second: 0.001,
+            burst,
+        };
+        let ip = IpLimits::new(rate(1.0));
+        let mut limits = Limits {
+            connection: TokenBucket::new(rate(2.0)),
+            ip: &ip,
+            peer_ip: [127, 0, 0, 1].into(),
+        };
+
+        assert!(limits.check().is_ok());
+        // The address is out of tokens, so this is refused, but the
+        // connection should still have its second token afterwards.
+        assert!(limits.check().is_err());
+        assert!(limits.connection.try_take());
+        assert!(!limits.connection.try_take());
+    }
+}
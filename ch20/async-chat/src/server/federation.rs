@@ -0,0 +1,296 @@
+//! Links between chat servers, so that members of the same group on different
+//! nodes see each other's posts.
+//!
+//! Each node dials every peer named with `--peer` and, over that link, sends
+//! the peer posts made by its own clients. The peer answers with the names of
+//! the groups it has, so only posts someone over there could see are sent.
+//! Posts that arrive from a peer are delivered to local members but never
+//! passed on, so a post can't loop between nodes; in exchange, every node must
+//! list every other node as a peer.
+//!
+//! Only the text of posts travels between nodes. Each node numbers the
+//! messages it delivers itself, and edits, deletions and reactions stay on the
+//! node where they were made.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    rt::{self, TcpListener, TcpStream},
+    utils::{self, ChatResult},
+};
+use async_channel as channel;
+use futures_lite::{io::BufReader, FutureExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, warn, Instrument};
+
+use super::{shutdown::Shutdown, state::ServerState};
+
+#[derive(Debug, Deserialize, Serialize)]
+enum PeerPacket {
+    /// The dialing node's first packet.
+    Hello { node_id: Arc<String> },
+    /// To the dialing node: we have a group by this name now.
+    Subscribe { group_name: Arc<String> },
+    /// To the dialing node: we no longer have a group by this name.
+    Unsubscribe { group_name: Arc<String> },
+    /// From the dialing node: one of its clients posted this.
+    Post {
+        group_name: Arc<String>,
+        message: Arc<String>,
+    },
+}
+
+/// Posts waiting to go out over a link. If a peer falls this far behind, we
+/// drop its posts rather than let it hold up our own clients.
+const OUTGOING_QUEUE_LEN: usize = 1000;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct Federation {
+    node_id: Arc<String>,
+    /// Links we dial, which carry our posts to peers.
+    outgoing: Vec<Arc<OutgoingLink>>,
+    /// Queues feeding the links peers have dialed to us, which carry our
+    /// groups' names to them.
+    incoming: Mutex<Vec<channel::Sender<PeerPacket>>>,
+}
+
+struct OutgoingLink {
+    address: String,
+    state: Mutex<LinkState>,
+}
+
+#[derive(Default)]
+struct LinkState {
+    /// The groups the peer has told us it has.
+    interest: HashSet<Arc<String>>,
+    /// The queue feeding the link, while it's up.
+    queue: Option<channel::Sender<PeerPacket>>,
+}
+
+impl Federation {
+    pub fn new(node_id: String, peers: &[String]) -> Self {
+        Federation {
+            node_id: Arc::new(node_id),
+            outgoing: peers
+                .iter()
+                .map(|address| {
+                    Arc::new(OutgoingLink {
+                        address: address.clone(),
+                        state: Mutex::new(LinkState::default()),
+                    })
+                })
+                .collect(),
+            incoming: Mutex::new(vec![]),
+        }
+    }
+
+    /// Send a post made by one of our own clients to every peer that has a
+    /// group by that name.
+    pub fn relay(&self, group_name: &Arc<String>, message: &Arc<String>) {
+        for link in &self.outgoing {
+            let state = link.state.lock().unwrap();
+            let queue = match &state.queue {
+                Some(queue) if state.interest.contains(group_name) => queue,
+                _ => continue,
+            };
+            let post = PeerPacket::Post {
+                group_name: group_name.clone(),
+                message: message.clone(),
+            };
+            if queue.try_send(post).is_err() {
+                warn!(peer = %link.address, group = %group_name, "peer lagging, dropped post");
+            }
+        }
+    }
+
+    pub fn group_created(&self, group_name: &Arc<String>) {
+        self.announce(|| PeerPacket::Subscribe {
+            group_name: group_name.clone(),
+        });
+    }
+
+    pub fn group_removed(&self, group_name: &Arc<String>) {
+        self.announce(|| PeerPacket::Unsubscribe {
+            group_name: group_name.clone(),
+        });
+    }
+
+    fn announce(&self, packet: impl Fn() -> PeerPacket) {
+        let mut incoming = self.incoming.lock().unwrap();
+        // The queues are unbounded, so this only fails once a link is gone.
+        incoming.retain(|queue| queue.try_send(packet()).is_ok());
+    }
+}
+
+/// Keep a link up to every configured peer until shutdown.
+pub fn dial_peers(state: &Arc<ServerState>, shutdown: &Shutdown) {
+    for link in &state.federation.outgoing {
+        let span = info_span!("peer_link", peer = %link.address);
+        let keeper = keep_linked(state.clone(), link.clone(), shutdown.clone());
+        rt::spawn(keeper.instrument(span));
+    }
+}
+
+async fn keep_linked(state: Arc<ServerState>, link: Arc<OutgoingLink>, mut shutdown: Shutdown) {
+    let mut backoff = MIN_BACKOFF;
+    while !shutdown.is_triggered() {
+        let mut linked = false;
+        let result = run_outgoing(&state, &link, &mut linked)
+            .race(async {
+                shutdown.wait().await;
+                Ok(())
+            })
+            .await;
+
+        *link.state.lock().unwrap() = LinkState::default();
+        match result {
+            Err(error) if linked => warn!(%error, "link lost"),
+            Err(error) => info!(%error, ?backoff, "peer unreachable, will retry"),
+            Ok(()) if linked => info!("link closed"),
+            Ok(()) => {}
+        }
+
+        if linked {
+            backoff = MIN_BACKOFF;
+        }
+        async {
+            rt::sleep(backoff).await;
+        }
+        .race(shutdown.wait())
+        .await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn run_outgoing(
+    state: &ServerState,
+    link: &OutgoingLink,
+    linked: &mut bool,
+) -> ChatResult<()> {
+    let socket = TcpStream::connect(&link.address).await?;
+    let (from_peer, mut to_peer) = socket.into_split();
+    let hello = PeerPacket::Hello {
+        node_id: state.federation.node_id.clone(),
+    };
+    utils::send_as_json(&mut to_peer, &hello).await?;
+
+    let (queue, posts) = channel::bounded(OUTGOING_QUEUE_LEN);
+    link.state.lock().unwrap().queue = Some(queue);
+    *linked = true;
+    info!("link up");
+
+    let from_peer =
+        utils::receive_as_json_limited(BufReader::new(from_peer), state.config.max_line_len);
+    let read_interest = async {
+        futures_lite::pin!(from_peer);
+        while let Some(packet) = from_peer.next().await {
+            let mut link_state = link.state.lock().unwrap();
+            match packet? {
+                PeerPacket::Subscribe { group_name } => {
+                    link_state.interest.insert(group_name);
+                }
+                PeerPacket::Unsubscribe { group_name } => {
+                    link_state.interest.remove(&group_name);
+                }
+                other => return Err(format!("unexpected packet from peer: {:?}", other).into()),
+            }
+        }
+        Ok(())
+    };
+    let write_posts = async {
+        while let Ok(post) = posts.recv().await {
+            utils::send_as_json(&mut to_peer, &post).await?;
+        }
+        Ok(())
+    };
+    read_interest.race(write_posts).await
+}
+
+/// Accept links from peers until shutdown.
+pub async fn serve_peers(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    mut shutdown: Shutdown,
+) -> ChatResult<()> {
+    info!(address = %listener.local_addr()?, "accepting peer links");
+    loop {
+        let accepted = async { Some(listener.accept().await) }
+            .race(async {
+                shutdown.wait().await;
+                None
+            })
+            .await;
+        let (socket, address) = match accepted {
+            Some(accept_result) => accept_result?,
+            None => return Ok(()),
+        };
+
+        let state = state.clone();
+        let link = async move {
+            if let Err(error) = serve_peer(socket, &state).await {
+                warn!(%error, "incoming link lost");
+            }
+        };
+        rt::spawn(link.instrument(info_span!("incoming_link", %address)));
+    }
+}
+
+async fn serve_peer(socket: TcpStream, state: &ServerState) -> ChatResult<()> {
+    let federation = &state.federation;
+    let (from_peer, mut to_peer) = socket.into_split();
+    let from_peer =
+        utils::receive_as_json_limited(BufReader::new(from_peer), state.config.max_line_len);
+    futures_lite::pin!(from_peer);
+
+    let peer_id = match from_peer.next().await.transpose()? {
+        Some(PeerPacket::Hello { node_id }) => node_id,
+        other => return Err(format!("expected Hello from peer, got {:?}", other).into()),
+    };
+    if peer_id == federation.node_id {
+        return Err("peer has our own node id; is this node peered with itself?".into());
+    }
+    info!(peer = %peer_id, "incoming link up");
+
+    // Register for announcements before listing the groups we have, so that a
+    // group created in between is announced at least once.
+    let (queue, announcements) = channel::unbounded();
+    federation.incoming.lock().unwrap().push(queue.clone());
+    for group_name in state.groups.names() {
+        queue.try_send(PeerPacket::Subscribe { group_name })?;
+    }
+
+    let write_announcements = async {
+        while let Ok(packet) = announcements.recv().await {
+            utils::send_as_json(&mut to_peer, &packet).await?;
+        }
+        Ok(())
+    };
+    let read_posts = async {
+        while let Some(packet) = from_peer.next().await {
+            match packet? {
+                PeerPacket::Post {
+                    group_name,
+                    message,
+                } => {
+                    state.metrics.message_from_peer();
+                    if let Some(group) = state.groups.get(&group_name) {
+                        group.deliver(message);
+                    }
+                }
+                other => return Err(format!("unexpected packet from peer: {:?}", other).into()),
+            }
+        }
+        Ok(())
+    };
+
+    let result = read_posts.race(write_announcements).await;
+    queue.close();
+    info!(peer = %peer_id, "incoming link closed");
+    result
+}
@@ -0,0 +1,288 @@
+//! A chat group.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{warn, Instrument};
+
+use crate::{rt, AttachmentId, FromServer, MessageId};
+
+use super::{
+    attachments::Attachment,
+    connection::{Author, Outbound},
+    federation::Federation,
+    metrics::Metrics,
+};
+
+/// How many of a group's latest messages can still be edited, deleted or
+/// reacted to.
+const HISTORY_LEN: usize = 1000;
+
+pub struct Group {
+    name: Arc<String>,
+    sender: broadcast::Sender<FromServer>,
+    history: Mutex<History>,
+    metrics: Arc<Metrics>,
+    federation: Arc<Federation>,
+}
+
+/// The group's latest messages, oldest first. `recent[i]` has id
+/// `first_id + i`, deleted messages included.
+struct History {
+    first_id: MessageId,
+    recent: VecDeque<Posted>,
+}
+
+struct Posted {
+    /// Who posted this, or `None` if it came from a peer node.
+    author: Option<Author>,
+    deleted: bool,
+    /// Who has reacted with what.
+    reactions: HashSet<(Author, Arc<String>)>,
+}
+
+impl Group {
+    pub fn new(name: Arc<String>, metrics: Arc<Metrics>, federation: Arc<Federation>) -> Group {
+        let (sender, _receiver) = broadcast::channel(1000);
+        Group {
+            name,
+            sender,
+            history: Mutex::new(History {
+                first_id: 1,
+                recent: VecDeque::new(),
+            }),
+            metrics,
+            federation,
+        }
+    }
+
+    /// Subscribe `outbound` to this group. Cancelling the returned task
+    /// unsubscribes it again.
+    pub fn join(&self, outbound: Arc<Outbound>) -> rt::JoinHandle<()> {
+        let receiver = self.sender.subscribe();
+        let subscriber =
+            handle_subscriber(self.name.clone(), receiver, outbound, self.metrics.clone());
+        // Log under the joining connection's span, so lag reports name the peer.
+        rt::spawn(subscriber.in_current_span())
+    }
+
+    pub fn member_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Post a message from one of our own clients, here and on peer nodes.
+    pub fn post(&self, author: Author, message: Arc<String>) -> MessageId {
+        self.metrics.message_posted();
+        self.federation.relay(&self.name, &message);
+        self.add(Some(author), message)
+    }
+
+    /// Pass a message from a peer node to this node's members only.
+    pub fn deliver(&self, message: Arc<String>) -> MessageId {
+        self.add(None, message)
+    }
+
+    fn add(&self, author: Option<Author>, message: Arc<String>) -> MessageId {
+        // Number and send under the lock, so members see ids in order.
+        let mut history = self.history.lock().unwrap();
+        if history.recent.len() == HISTORY_LEN {
+            history.recent.pop_front();
+            history.first_id += 1;
+        }
+        history.recent.push_back(Posted {
+            author,
+            deleted: false,
+            reactions: HashSet::new(),
+        });
+        let id = history.first_id + history.recent.len() as MessageId - 1;
+        self.send(FromServer::Message {
+            group_name: self.name.clone(),
+            id,
+            message,
+        });
+        id
+    }
+
+    pub fn edit(&self, author: &Author, id: MessageId, message: Arc<String>) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        self.find_own(&mut history, author, id)?;
+        self.send(FromServer::Edited {
+            group_name: self.name.clone(),
+            id,
+            message,
+        });
+        Ok(())
+    }
+
+    pub fn delete(&self, author: &Author, id: MessageId) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        self.find_own(&mut history, author, id)?.deleted = true;
+        self.send(FromServer::Deleted {
+            group_name: self.name.clone(),
+            id,
+        });
+        Ok(())
+    }
+
+    pub fn react(
+        &self,
+        reactor: Author,
+        id: MessageId,
+        reaction: Arc<String>,
+    ) -> Result<(), String> {
+        let mut history = self.history.lock().unwrap();
+        let posted = self.find(&mut history, id)?;
+        if !posted.reactions.insert((reactor, reaction.clone())) {
+            return Err(format!(
+                "You already reacted to message {id} with {reaction}"
+            ));
+        }
+        self.send(FromServer::Reacted {
+            group_name: self.name.clone(),
+            id,
+            reaction,
+        });
+        Ok(())
+    }
+
+    /// Tell the members about a newly uploaded attachment.
+    pub fn announce_attachment(&self, attachment_id: AttachmentId, attachment: &Attachment) {
+        self.send(FromServer::Attachment {
+            group_name: self.name.clone(),
+            attachment_id,
+            file_name: attachment.file_name.clone(),
+            size: attachment.data.len() as u64,
+            sha256: attachment.sha256.clone(),
+        });
+    }
+
+    fn find<'h>(&self, history: &'h mut History, id: MessageId) -> Result<&'h mut Posted, String> {
+        id.checked_sub(history.first_id)
+            .and_then(|index| history.recent.get_mut(index as usize))
+            .filter(|posted| !posted.deleted)
+            .ok_or_else(|| format!("No recent message {id} in {}", self.name))
+    }
+
+    /// Like `find`, but only if `author` posted the message.
+    fn find_own<'h>(
+        &self,
+        history: &'h mut History,
+        author: &Author,
+        id: MessageId,
+    ) -> Result<&'h mut Posted, String> {
+        let posted = self.find(history, id)?;
+        if posted.author.as_ref() != Some(author) {
+            return Err(format!("Message {id} in {} isn't yours", self.name));
+        }
+        Ok(posted)
+    }
+
+    fn send(&self, event: FromServer) {
+        // It's fine if nobody's listening.
+        let _ignored = self.sender.send(event);
+    }
+}
+
+async fn handle_subscriber(
+    group_name: Arc<String>,
+    mut receiver: broadcast::Receiver<FromServer>,
+    outbound: Arc<Outbound>,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        let packet = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                warn!(group = %group_name, dropped = n, "subscriber lagging");
+                metrics.messages_dropped(n);
+                FromServer::Error(format!("Dropped {} messages from {}.", n, group_name))
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if outbound.send(packet).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Arc<String> {
+        Arc::new(s.to_string())
+    }
+
+    fn connection(id: u64) -> Author {
+        Author::Connection(id)
+    }
+
+    #[test]
+    fn test_author_checks() {
+        let federation = Federation::new("test".to_string(), &[]);
+        let group = Group::new(text("Dogs"), Arc::new(Metrics::new()), Arc::new(federation));
+        let mut receiver = group.sender.subscribe();
+
+        let mine = group.post(connection(1), text("Samoyeds rock!"));
+        let theirs = group.deliver(text("So do huskies."));
+        assert_eq!((mine, theirs), (1, 2));
+
+        assert!(group
+            .edit(&connection(1), mine, text("Samoyeds rule!"))
+            .is_ok());
+        assert!(group
+            .edit(&connection(2), mine, text("Cats rule!"))
+            .is_err());
+        assert!(group.delete(&connection(1), theirs).is_err());
+        assert!(group.react(connection(2), mine, text("+1")).is_ok());
+        assert!(group.react(connection(2), mine, text("+1")).is_err());
+        assert!(group.react(connection(1), mine, text("+1")).is_ok());
+        assert!(group.delete(&connection(1), mine).is_ok());
+        assert!(group
+            .edit(&connection(1), mine, text("Never mind"))
+            .is_err());
+        assert!(group.react(connection(1), 3, text("?")).is_err());
+
+        let mut events = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 6);
+        assert_eq!(
+            events[2],
+            FromServer::Edited {
+                group_name: text("Dogs"),
+                id: 1,
+                message: text("Samoyeds rule!"),
+            }
+        );
+        assert_eq!(
+            events[5],
+            FromServer::Deleted {
+                group_name: text("Dogs"),
+                id: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let federation = Federation::new("test".to_string(), &[]);
+        let group = Group::new(text("Dogs"), Arc::new(Metrics::new()), Arc::new(federation));
+        for _ in 0..HISTORY_LEN + 5 {
+            group.post(connection(1), text("woof"));
+        }
+        assert!(group.edit(&connection(1), 5, text("old")).is_err());
+        assert!(group.edit(&connection(1), 6, text("still here")).is_ok());
+        assert!(group
+            .edit(&connection(1), HISTORY_LEN as MessageId + 5, text("new"))
+            .is_ok());
+        assert!(group
+            .edit(&connection(1), HISTORY_LEN as MessageId + 6, text("future"))
+            .is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::rt;
+
+use super::{connection::Outbound, federation::Federation, group::Group, metrics::Metrics};
+
+pub struct GroupTable {
+    groups: Mutex<HashMap<Arc<String>, Entry>>,
+    metrics: Arc<Metrics>,
+    federation: Arc<Federation>,
+}
+
+struct Entry {
+    group: Arc<Group>,
+    /// When `reap_empty` first noticed this group had no members.
+    empty_since: Option<Instant>,
+}
+
+impl GroupTable {
+    pub fn new(metrics: Arc<Metrics>, federation: Arc<Federation>) -> Self {
+        GroupTable {
+            groups: Mutex::new(HashMap::new()),
+            metrics,
+            federation,
+        }
+    }
+
+    pub fn get(&self, name: &String) -> Option<Arc<Group>> {
+        // self.groups.lock().unwrap().get(name).map(|entry| entry.group.clone())
+        let map_guard = self.groups.lock().unwrap();
+        let entry = map_guard.get(name);
+        entry.map(|entry| entry.group.clone())
+    }
+
+    /// Subscribe `outbound` to the group called `name`, creating it if need be.
+    ///
+    /// This subscribes while still holding the table's lock, so `reap_empty`
+    /// can't remove the group between our finding it and joining it.
+    pub fn join(&self, name: Arc<String>, outbound: Arc<Outbound>) -> rt::JoinHandle<()> {
+        let mut map_guard = self.groups.lock().unwrap();
+        let entry = map_guard.entry(name.clone()).or_insert_with(|| {
+            self.federation.group_created(&name);
+            Entry {
+                group: Arc::new(Group::new(
+                    name,
+                    self.metrics.clone(),
+                    self.federation.clone(),
+                )),
+                empty_since: None,
+            }
+        });
+        entry.empty_since = None;
+        entry.group.join(outbound)
+    }
+
+    pub fn names(&self) -> Vec<Arc<String>> {
+        let map_guard = self.groups.lock().unwrap();
+        map_guard.keys().cloned().collect()
+    }
+
+    /// The number of members in each group, by name.
+    pub fn member_counts(&self) -> BTreeMap<String, usize> {
+        let map_guard = self.groups.lock().unwrap();
+        map_guard
+            .iter()
+            .map(|(name, entry)| (name.to_string(), entry.group.member_count()))
+            .collect()
+    }
+
+    /// Remove groups that have had no members for at least `grace`, returning
+    /// their names.
+    pub fn reap_empty(&self, grace: Duration) -> Vec<Arc<String>> {
+        let now = Instant::now();
+        let mut reaped = vec![];
+        let mut map_guard = self.groups.lock().unwrap();
+        map_guard.retain(|name, entry| {
+            if entry.group.member_count() > 0 {
+                entry.empty_since = None;
+                return true;
+            }
+            let empty_since = *entry.empty_since.get_or_insert(now);
+            if now.duration_since(empty_since) < grace {
+                return true;
+            }
+            reaped.push(name.clone());
+            false
+        });
+        for name in &reaped {
+            self.federation.group_removed(name);
+        }
+        reaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_empty(table: &GroupTable, name: &str) {
+        let name = Arc::new(name.to_string());
+        table.groups.lock().unwrap().insert(
+            name.clone(),
+            Entry {
+                group: Arc::new(Group::new(
+                    name,
+                    table.metrics.clone(),
+                    table.federation.clone(),
+                )),
+                empty_since: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_reap_empty() {
+        let federation = Federation::new("test".to_string(), &[]);
+        let table = GroupTable::new(Arc::new(Metrics::new()), Arc::new(federation));
+        create_empty(&table, "Dogs");
+
+        assert!(table.reap_empty(Duration::from_secs(60)).is_empty());
+        assert!(table.get(&"Dogs".to_string()).is_some());
+        assert_eq!(table.member_counts().get("Dogs"), Some(&0));
+
+        let reaped = table.reap_empty(Duration::ZERO);
+        assert_eq!(reaped, vec![Arc::new("Dogs".to_string())]);
+        assert!(table.get(&"Dogs".to_string()).is_none());
+    }
+}
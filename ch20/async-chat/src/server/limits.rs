@@ -0,0 +1,249 @@
+//! Protection against clients that post too fast or connect too often.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// How fast a client may send requests: `per_second` on average, with up to
+/// `burst` at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// A token bucket: each request takes a token, and tokens trickle back in at
+/// `rate.per_second`, up to `rate.burst` of them.
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    /// Whether `try_take` would succeed, without taking anything.
+    pub fn has_token(&mut self) -> bool {
+        self.has_token_at(Instant::now())
+    }
+
+    fn has_token_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        if self.has_token_at(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate.burst
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate.per_second).min(self.rate.burst);
+        self.last_refill = now;
+    }
+}
+
+/// Token buckets shared by all connections from the same address, so opening
+/// more connections doesn't buy a client more requests.
+pub struct IpLimits {
+    rate: Rate,
+    table: Mutex<IpTable>,
+}
+
+struct IpTable {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    /// Sweep out idle buckets once the table grows this large.
+    prune_at: usize,
+}
+
+const MIN_PRUNE_AT: usize = 1024;
+
+impl IpLimits {
+    pub fn new(rate: Rate) -> Self {
+        IpLimits {
+            rate,
+            table: Mutex::new(IpTable {
+                buckets: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+            }),
+        }
+    }
+
+    pub fn try_take(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        // A full bucket is the same as no bucket, so forget those; otherwise
+        // we'd remember every address that ever connected.
+        if table.buckets.len() >= table.prune_at {
+            table.buckets.retain(|_, bucket| !bucket.is_full_at(now));
+            table.prune_at = MIN_PRUNE_AT.max(table.buckets.len() * 2);
+        }
+        let rate = self.rate;
+        table
+            .buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(rate))
+            .try_take_at(now)
+    }
+}
+
+/// Counts live connections against a cap. Each admitted connection holds a
+/// `ConnectionPermit`, which gives its slot back when dropped.
+pub struct ConnectionCount {
+    max: usize,
+    live: Arc<AtomicUsize>,
+}
+
+pub struct ConnectionPermit(Arc<AtomicUsize>);
+
+impl ConnectionCount {
+    pub fn new(max: usize) -> Self {
+        ConnectionCount {
+            max,
+            live: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn try_admit(&self) -> Option<ConnectionPermit> {
+        let admitted = self
+            .live
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| {
+                (live < self.max).then(|| live + 1)
+            })
+            .is_ok();
+        admitted.then(|| ConnectionPermit(self.live.clone()))
+    }
+
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Counts bytes held for some purpose against a server-wide cap. Each
+/// `ByteReservation` gives its bytes back when dropped.
+pub struct ByteBudget {
+    max: usize,
+    used: Arc<AtomicUsize>,
+}
+
+pub struct ByteReservation {
+    used: Arc<AtomicUsize>,
+    bytes: usize,
+}
+
+impl ByteBudget {
+    pub fn new(max: usize) -> Self {
+        ByteBudget {
+            max,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn try_reserve(&self, bytes: usize) -> Option<ByteReservation> {
+        let reserved = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.max)
+            })
+            .is_ok();
+        reserved.then(|| ByteReservation {
+            used: self.used.clone(),
+            bytes,
+        })
+    }
+}
+
+impl Drop for ByteReservation {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(Rate {
+            per_second: 2.0,
+            burst: 3.0,
+        });
+        let start = bucket.last_refill;
+
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.has_token_at(start));
+        assert!(bucket.has_token_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(!bucket.has_token_at(start));
+        assert!(!bucket.try_take_at(start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.is_full_at(much_later));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn test_connection_count() {
+        let count = ConnectionCount::new(2);
+        let first = count.try_admit().unwrap();
+        let _second = count.try_admit().unwrap();
+        assert!(count.try_admit().is_none());
+
+        drop(first);
+        assert!(count.try_admit().is_some());
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let budget = ByteBudget::new(100);
+        let first = budget.try_reserve(60).unwrap();
+        assert!(budget.try_reserve(41).is_none());
+        let _second = budget.try_reserve(40).unwrap();
+        assert!(budget.try_reserve(1).is_none());
+
+        drop(first);
+        assert!(budget.try_reserve(60).is_some());
+        assert!(budget.try_reserve(usize::MAX).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+//! Counters for the admin endpoint.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// How far back `messages_per_second` looks.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+pub struct Metrics {
+    messages_posted: AtomicU64,
+    messages_from_peers: AtomicU64,
+    lag_drops: AtomicU64,
+    /// Recent readings of `messages_posted`, oldest first, for working out the
+    /// posting rate.
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+/// Everything the admin endpoint reports, as of one moment.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub connected_clients: usize,
+    pub groups: usize,
+    pub members: BTreeMap<String, usize>,
+    pub messages_posted: u64,
+    pub messages_per_second: f64,
+    /// Posts that arrived over federation links. These aren't counted in
+    /// `messages_posted`, which is posts by this node's own clients.
+    pub messages_from_peers: u64,
+    pub lag_drops: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            messages_posted: AtomicU64::new(0),
+            messages_from_peers: AtomicU64::new(0),
+            lag_drops: AtomicU64::new(0),
+            samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn message_posted(&self) {
+        self.messages_posted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_from_peer(&self) {
+        self.messages_from_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn messages_dropped(&self, count: u64) {
+        self.lag_drops.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn messages_posted(&self) -> u64 {
+        self.messages_posted.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_from_peers(&self) -> u64 {
+        self.messages_from_peers.load(Ordering::Relaxed)
+    }
+
+    pub fn lag_drops(&self) -> u64 {
+        self.lag_drops.load(Ordering::Relaxed)
+    }
+
+    /// Take a reading of the post counter. Call this regularly, say once a
+    /// second, to keep `messages_per_second` current.
+    pub fn sample(&self) {
+        self.sample_at(Instant::now(), self.messages_posted());
+    }
+
+    fn sample_at(&self, now: Instant, posted: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, posted));
+        while let Some(&(oldest, _)) = samples.front() {
+            if now.duration_since(oldest) <= RATE_WINDOW {
+                break;
+            }
+            samples.pop_front();
+        }
+    }
+
+    /// The average posting rate over the samples in the window.
+    pub fn messages_per_second(&self) -> f64 {
+        let samples = self.samples.lock().unwrap();
+        match (samples.front(), samples.back()) {
+            (Some(&(start, first)), Some(&(end, last))) if end > start => {
+                (last - first) as f64 / end.duration_since(start).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_per_second() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.messages_per_second(), 0.0);
+
+        let start = Instant::now();
+        metrics.sample_at(start, 0);
+        metrics.sample_at(start + Duration::from_secs(2), 10);
+        assert_eq!(metrics.messages_per_second(), 5.0);
+
+        // The first sample has aged out of the window by now.
+        metrics.sample_at(start + Duration::from_secs(12), 30);
+        assert_eq!(metrics.messages_per_second(), 2.0);
+    }
+}
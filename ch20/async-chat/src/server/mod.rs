@@ -0,0 +1,209 @@
+//! The chat server, as a library, so that tests can run one in-process.
+//!
+//! `Server::bind` binds every address in the `Config`; give port 0 to have the
+//! system pick a free one, and ask `local_addr` which it was. `Server::run`
+//! then serves until shutdown is triggered.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_lite::FutureExt;
+use tokio::sync::mpsc;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    rt::{self, TcpListener},
+    utils::{self, ChatResult},
+    FromServer,
+};
+
+use self::{connection::serve, state::ServerState};
+
+pub use self::{
+    config::Config,
+    shutdown::{Shutdown, Trigger},
+};
+
+mod admin;
+mod attachments;
+pub mod config;
+mod connection;
+mod federation;
+mod group;
+mod group_table;
+mod limits;
+mod metrics;
+pub mod shutdown;
+mod state;
+
+pub struct Server {
+    listener: TcpListener,
+    admin_listener: Option<TcpListener>,
+    peer_listener: Option<TcpListener>,
+    state: Arc<ServerState>,
+}
+
+impl Server {
+    pub async fn bind(config: Config) -> ChatResult<Server> {
+        let listener = TcpListener::bind(&config.address).await?;
+        let admin_listener = match &config.admin_address {
+            Some(address) => Some(TcpListener::bind(address).await?),
+            None => None,
+        };
+        let peer_listener = match &config.federation_address {
+            Some(address) => Some(TcpListener::bind(address).await?),
+            None => None,
+        };
+        Ok(Server {
+            listener,
+            admin_listener,
+            peer_listener,
+            state: Arc::new(ServerState::new(config)),
+        })
+    }
+
+    /// The address clients should connect to.
+    pub fn local_addr(&self) -> ChatResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// The admin endpoint's address, if the config asked for one.
+    pub fn admin_addr(&self) -> ChatResult<Option<SocketAddr>> {
+        Ok(self
+            .admin_listener
+            .as_ref()
+            .map(|l| l.local_addr())
+            .transpose()?)
+    }
+
+    /// The address peer nodes should link to, if the config asked for one.
+    pub fn federation_addr(&self) -> ChatResult<Option<SocketAddr>> {
+        Ok(self
+            .peer_listener
+            .as_ref()
+            .map(|l| l.local_addr())
+            .transpose()?)
+    }
+
+    /// Serve clients until `shutdown` is triggered, then give their
+    /// connections up to the configured drain timeout to finish.
+    pub async fn run(self, mut shutdown: Shutdown) -> ChatResult<()> {
+        let Server {
+            listener,
+            admin_listener,
+            peer_listener,
+            state,
+        } = self;
+        info!(address = %listener.local_addr()?, "listening");
+
+        if let Some(admin_listener) = admin_listener {
+            let serving = admin::serve_admin(admin_listener, state.clone(), shutdown.clone());
+            rt::spawn(async move {
+                if let Err(error) = serving.await {
+                    warn!(%error, "admin endpoint failed");
+                }
+            });
+        }
+        if let Some(peer_listener) = peer_listener {
+            let serving = federation::serve_peers(peer_listener, state.clone(), shutdown.clone());
+            rt::spawn(async move {
+                if let Err(error) = serving.await {
+                    warn!(%error, "federation listener failed");
+                }
+            });
+        }
+        federation::dial_peers(&state, &shutdown);
+        rt::spawn(reap_empty_groups(state.clone(), shutdown.clone()));
+        rt::spawn(sample_metrics(state.clone(), shutdown.clone()));
+
+        // Every connection task holds a clone of `still_serving`; once they
+        // have all been dropped, `all_done.recv()` returns `None`.
+        let (still_serving, mut all_done) = mpsc::channel::<()>(1);
+
+        loop {
+            let accepted = async { Some(listener.accept().await) }
+                .race(async {
+                    shutdown.wait().await;
+                    None
+                })
+                .await;
+            let (socket, peer) = match accepted {
+                Some(accept_result) => accept_result?,
+                None => break,
+            };
+
+            let permit = match state.connections.try_admit() {
+                Some(permit) => permit,
+                None => {
+                    warn!(%peer, "too many connections, refusing");
+                    rt::spawn(async move {
+                        let (_, mut to_client) = socket.into_split();
+                        let refusal =
+                            FromServer::Error("Too many connections, try again later".to_string());
+                        let _ignored = utils::send_as_json(&mut to_client, &refusal).await;
+                    });
+                    continue;
+                }
+            };
+
+            let state = state.clone();
+            let shutdown = shutdown.clone();
+            let still_serving = still_serving.clone();
+            let connection = async move {
+                info!("connection opened");
+                match serve(socket, state, shutdown).await {
+                    Ok(()) => info!("connection closed"),
+                    Err(error) => warn!(%error, "connection closed with error"),
+                }
+                drop(permit);
+                drop(still_serving);
+            };
+            rt::spawn(connection.instrument(info_span!("connection", %peer)));
+        }
+
+        drop(listener);
+        drop(still_serving);
+        if rt::timeout(state.config.drain_timeout, all_done.recv())
+            .await
+            .is_err()
+        {
+            warn!("gave up waiting for connections to drain");
+        }
+
+        Ok(())
+    }
+}
+
+/// Periodically forget groups that have had no members for the grace period,
+/// until `shutdown` is triggered.
+async fn reap_empty_groups(state: Arc<ServerState>, mut shutdown: Shutdown) {
+    let grace = state.config.group_grace;
+    // Sweeping twice per grace period means a group goes at most 1.5 grace
+    // periods after its last member leaves.
+    while sleep_unless_shutdown(grace / 2, &mut shutdown).await {
+        for name in state.groups.reap_empty(grace) {
+            info!(group = %name, ?grace, "removed empty group");
+        }
+    }
+}
+
+/// Keep the posting rate in the metrics up to date, until `shutdown` is
+/// triggered.
+async fn sample_metrics(state: Arc<ServerState>, mut shutdown: Shutdown) {
+    while sleep_unless_shutdown(Duration::from_secs(1), &mut shutdown).await {
+        state.metrics.sample();
+    }
+}
+
+/// Sleep for `duration`, returning true, or false if shutdown is triggered
+/// first.
+async fn sleep_unless_shutdown(duration: Duration, shutdown: &mut Shutdown) -> bool {
+    async {
+        rt::sleep(duration).await;
+        true
+    }
+    .race(async {
+        shutdown.wait().await;
+        false
+    })
+    .await
+}
@@ -0,0 +1,61 @@
+//! Server-wide shutdown notification, triggered by SIGINT or SIGTERM.
+
+use std::thread;
+
+use crate::utils::ChatResult;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tokio::sync::watch;
+
+/// A handle for waiting until the server starts shutting down. Cheap to clone;
+/// each connection task keeps its own.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+pub struct Trigger(watch::Sender<bool>);
+
+pub fn channel() -> (Trigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (Trigger(sender), Shutdown(receiver))
+}
+
+impl Trigger {
+    pub fn trigger(&self) {
+        let _ignored = self.0.send(true);
+    }
+}
+
+impl Shutdown {
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until shutdown has been triggered. Returns immediately if it
+    /// already has been, or if the `Trigger` is gone.
+    pub async fn wait(&mut self) {
+        while !self.is_triggered() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Start a thread that triggers shutdown on the first SIGINT or SIGTERM, and
+/// exits the process outright on the second.
+pub fn trigger_on_signals(trigger: Trigger) -> ChatResult<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            tracing::info!(signal, "shutting down, repeat the signal to force");
+            trigger.trigger();
+        }
+        if signals.next().is_some() {
+            std::process::exit(1);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! State shared by every connection.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use super::{
+    attachments::AttachmentStore,
+    config::Config,
+    connection::ConnectionId,
+    federation::Federation,
+    group_table::GroupTable,
+    limits::{ByteBudget, ConnectionCount, IpLimits},
+    metrics::{Metrics, Snapshot},
+};
+
+pub struct ServerState {
+    pub config: Config,
+    pub groups: GroupTable,
+    pub ip_limits: IpLimits,
+    pub connections: ConnectionCount,
+    pub metrics: Arc<Metrics>,
+    pub federation: Arc<Federation>,
+    pub attachments: AttachmentStore,
+    /// Bytes set aside for uploads in progress.
+    pub upload_buffer: ByteBudget,
+    next_connection_id: AtomicU64,
+}
+
+impl ServerState {
+    pub fn new(config: Config) -> Self {
+        let metrics = Arc::new(Metrics::new());
+        let node_id = config
+            .node_id
+            .clone()
+            .unwrap_or_else(|| config.address.clone());
+        let federation = Arc::new(Federation::new(node_id, &config.peers));
+        ServerState {
+            groups: GroupTable::new(metrics.clone(), federation.clone()),
+            ip_limits: IpLimits::new(config.ip_rate),
+            connections: ConnectionCount::new(config.max_connections),
+            metrics,
+            federation,
+            attachments: AttachmentStore::new(config.attachment_store_size),
+            upload_buffer: ByteBudget::new(config.upload_buffer_size),
+            config,
+            next_connection_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn new_connection_id(&self) -> ConnectionId {
+        self.next_connection_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let members = self.groups.member_counts();
+        Snapshot {
+            connected_clients: self.connections.live(),
+            groups: members.len(),
+            members,
+            messages_posted: self.metrics.messages_posted(),
+            messages_per_second: self.metrics.messages_per_second(),
+            messages_from_peers: self.metrics.messages_from_peers(),
+            lag_drops: self.metrics.lag_drops(),
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use async_std::{io::prelude::BufReadExt, prelude::*};
+use futures_lite::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    Stream, StreamExt,
+};
 use serde::Serialize;
 use std::error::Error;
 
@@ -10,7 +13,7 @@ pub type ChatResult<T> = Result<T, ChatError>;
 
 pub async fn send_as_json<S, P>(outbound: &mut S, packet: &P) -> ChatResult<()>
 where
-    S: async_std::io::Write + Unpin,
+    S: AsyncWrite + Unpin,
     P: Serialize,
 {
     let mut json = serde_json::to_string(&packet)?;
@@ -23,7 +26,7 @@ use serde::de::DeserializeOwned;
 
 pub fn receive_as_json<S, P>(inbound: S) -> impl Stream<Item = ChatResult<P>>
 where
-    S: async_std::io::BufRead + Unpin,
+    S: AsyncBufRead + Unpin,
     P: DeserializeOwned,
 {
     inbound.lines().map(|line_result| -> ChatResult<P> {
@@ -32,3 +35,89 @@ where
         Ok(parsed)
     })
 }
+
+/// Like `receive_as_json`, but never buffer more than `max_line_len` bytes of
+/// a single line. A longer line yields an error and ends the stream, since we
+/// can no longer tell where the next packet starts.
+pub fn receive_as_json_limited<S, P>(
+    inbound: S,
+    max_line_len: usize,
+) -> impl Stream<Item = ChatResult<P>>
+where
+    S: AsyncBufRead + Unpin,
+    P: DeserializeOwned,
+{
+    futures_lite::stream::unfold(Some(inbound), move |inbound| async move {
+        let mut inbound = inbound?;
+        let mut line = Vec::new();
+        let mut limited = (&mut inbound).take(max_line_len as u64 + 1);
+        let result = match limited.read_until(b'\n', &mut line).await {
+            Ok(0) => return None,
+            Err(error) => Err(error.into()),
+            Ok(_) if line.last() != Some(&b'\n') && line.len() > max_line_len => {
+                let error = format!("line longer than {} bytes", max_line_len);
+                return Some((Err(error.into()), None));
+            }
+            Ok(_) => {
+                let trimmed = line.strip_suffix(b"\n").unwrap_or(&line);
+                let trimmed = trimmed.strip_suffix(b"\r").unwrap_or(trimmed);
+                serde_json::from_slice::<P>(trimmed).map_err(ChatError::from)
+            }
+        };
+        Some((result, Some(inbound)))
+    })
+}
+
+/// Serialize a byte vector as a base64 string, rather than as serde's default
+/// array of numbers. Use with `#[serde(with = "utils::base64_bytes")]`.
+pub mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// The SHA-256 hash of `data`, in lowercase hex, as attachments are labelled.
+pub fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FromClient;
+
+    #[test]
+    fn test_receive_as_json_limited() {
+        let input = "\"Pong\"\r\n\"Pong\"\n\"Pong\" \"Pong\" \"Pong\"\n\"Pong\"\n";
+        let received: Vec<ChatResult<FromClient>> =
+            crate::rt::block_on(receive_as_json_limited(input.as_bytes(), 10).collect());
+
+        assert_eq!(received.len(), 3);
+        assert_eq!(*received[0].as_ref().unwrap(), FromClient::Pong);
+        assert_eq!(*received[1].as_ref().unwrap(), FromClient::Pong);
+        assert_eq!(
+            received[2].as_ref().unwrap_err().to_string(),
+            "line longer than 10 bytes"
+        );
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
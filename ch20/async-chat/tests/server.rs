@@ -0,0 +1,779 @@
+//! End-to-end tests: a real server on an ephemeral port, with clients talking
+//! to it over TCP, all in this process.
+//!
+//! These run on whichever runtime the crate was built for, so run them under
+//! both: `cargo test`, and `cargo test --no-default-features --features tokio`.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_chat::{
+    client::{Download, Events},
+    rt::{self, TcpStream},
+    server::{shutdown, Config, Server, Trigger},
+    utils::{self, ChatResult},
+    ChatClient, FromClient, FromServer, MessageId, UploadId,
+};
+use futures_lite::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    FutureExt, StreamExt,
+};
+
+/// How long to wait for something the server should do promptly.
+const PATIENCE: Duration = Duration::from_secs(5);
+
+struct TestServer {
+    address: SocketAddr,
+    admin_address: SocketAddr,
+    federation_address: Option<SocketAddr>,
+    trigger: Trigger,
+    running: rt::JoinHandle<ChatResult<()>>,
+}
+
+impl TestServer {
+    /// Start a server with the given extra command-line options.
+    async fn start(options: &[&str]) -> TestServer {
+        let mut args = vec!["127.0.0.1:0", "--admin", "127.0.0.1:0"];
+        args.extend(options);
+        let config = Config::from_args(args.iter().map(|s| s.to_string())).unwrap();
+
+        let server = Server::bind(config).await.unwrap();
+        let address = server.local_addr().unwrap();
+        let admin_address = server.admin_addr().unwrap().unwrap();
+        let federation_address = server.federation_addr().unwrap();
+        let (trigger, shutdown) = shutdown::channel();
+        TestServer {
+            address,
+            admin_address,
+            federation_address,
+            trigger,
+            running: rt::spawn(server.run(shutdown)),
+        }
+    }
+
+    async fn connect(&self) -> (ChatClient, Events) {
+        ChatClient::connect(&self.address.to_string())
+            .await
+            .unwrap()
+    }
+
+    /// Fetch the admin endpoint's counters.
+    async fn metrics(&self) -> serde_json::Value {
+        let socket = TcpStream::connect(&self.admin_address.to_string())
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = socket.into_split();
+        writer
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        reader.read_to_string(&mut response).await.unwrap();
+        let (_head, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    /// Wait until `group` has `count` members. There's no reply to `Join`, so
+    /// this is how a test knows the server has seen one.
+    async fn wait_for_members(&self, group: &str, count: u64) {
+        self.wait_for(|metrics| metrics["members"][group].as_u64().unwrap_or(0) == count)
+            .await;
+    }
+
+    async fn wait_for_connections(&self, count: u64) {
+        self.wait_for(|metrics| metrics["connected_clients"] == count)
+            .await;
+    }
+
+    async fn wait_for(&self, done: impl Fn(&serde_json::Value) -> bool) {
+        let deadline = Instant::now() + PATIENCE;
+        loop {
+            let metrics = self.metrics().await;
+            if done(&metrics) {
+                return;
+            }
+            assert!(Instant::now() < deadline, "gave up waiting: {metrics}");
+            rt::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn stop(self) {
+        self.trigger.trigger();
+        self.running.await.unwrap();
+    }
+}
+
+async fn next_event(events: &mut Events) -> FromServer {
+    rt::timeout(PATIENCE, events.next())
+        .await
+        .expect("timed out waiting for the server")
+        .expect("server closed the connection")
+        .expect("error reading from the server")
+}
+
+/// Upload `data` with `client`, polling `events` meanwhile so the upload hears
+/// whether the server accepted it.
+async fn upload(
+    client: &ChatClient,
+    events: &mut Events,
+    group: &str,
+    file_name: &str,
+    data: &[u8],
+) -> ChatResult<UploadId> {
+    let polling = async {
+        let event = next_event(events).await;
+        panic!("unexpected {event:?} while uploading");
+    };
+    client.upload(group, file_name, data).or(polling).await
+}
+
+fn message(group: &str, id: MessageId, text: &str) -> FromServer {
+    FromServer::Message {
+        group_name: Arc::new(group.to_string()),
+        id,
+        message: Arc::new(text.to_string()),
+    }
+}
+
+/// Send raw lines, bypassing `ChatClient`, and read back what the server says.
+async fn raw_exchange(server: &TestServer, lines: &[u8]) -> Vec<FromServer> {
+    let socket = TcpStream::connect(&server.address.to_string())
+        .await
+        .unwrap();
+    let (reader, mut writer) = socket.into_split();
+    writer.write_all(lines).await.unwrap();
+    let replies = utils::receive_as_json(BufReader::new(reader));
+    rt::timeout(PATIENCE, replies.map(Result::unwrap).collect())
+        .await
+        .expect("server didn't close the connection")
+}
+
+#[test]
+fn test_fanout() {
+    rt::block_on(async {
+        let server = TestServer::start(&[]).await;
+        let mut dogs = vec![];
+        for _ in 0..3 {
+            let (client, events) = server.connect().await;
+            client.join("Dogs").await.unwrap();
+            dogs.push((client, events));
+        }
+        let (cat, mut cat_events) = server.connect().await;
+        cat.join("Cats").await.unwrap();
+        server.wait_for_members("Dogs", 3).await;
+        server.wait_for_members("Cats", 1).await;
+
+        dogs[0].0.post("Dogs", "Samoyeds rock!").await.unwrap();
+        cat.post("Cats", "Meow.").await.unwrap();
+        for (_client, events) in &mut dogs {
+            assert_eq!(
+                next_event(events).await,
+                message("Dogs", 1, "Samoyeds rock!")
+            );
+        }
+        assert_eq!(
+            next_event(&mut cat_events).await,
+            message("Cats", 1, "Meow.")
+        );
+
+        // Nothing from Cats reached the dogs.
+        dogs[1].0.post("Dogs", "Woof.").await.unwrap();
+        for (_client, events) in &mut dogs {
+            assert_eq!(next_event(events).await, message("Dogs", 2, "Woof."));
+        }
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_federation() {
+    rt::block_on(async {
+        // Peers have to be named when a server starts, so `north` can't dial
+        // `south`; but `south` dialing `north` is enough to carry posts made
+        // on `south` to `north`.
+        let north = TestServer::start(&["--node-id", "north", "--federation", "127.0.0.1:0"]).await;
+        let north_link = north.federation_address.unwrap().to_string();
+        let south = TestServer::start(&["--node-id", "south", "--peer", &north_link]).await;
+
+        let (listener, mut listener_events) = north.connect().await;
+        listener.join("Dogs").await.unwrap();
+        let (poster, mut poster_events) = south.connect().await;
+        poster.join("Dogs").await.unwrap();
+        north.wait_for_members("Dogs", 1).await;
+        south.wait_for_members("Dogs", 1).await;
+
+        // `south` only relays posts once the link is up and `north` has told
+        // it about its Dogs group, and nothing says when that has happened,
+        // so keep posting until one gets through.
+        let deadline = Instant::now() + PATIENCE;
+        let mut sent = 0;
+        let received = loop {
+            assert!(Instant::now() < deadline, "no post reached the other node");
+            sent += 1;
+            poster.post("Dogs", &format!("Woof {sent}")).await.unwrap();
+            assert_eq!(
+                next_event(&mut poster_events).await,
+                message("Dogs", sent, &format!("Woof {sent}"))
+            );
+            let arrived = rt::timeout(Duration::from_millis(100), listener_events.next()).await;
+            if let Ok(Some(Ok(event))) = arrived {
+                break event;
+            }
+        };
+        match received {
+            FromServer::Message {
+                group_name,
+                message,
+                ..
+            } => {
+                assert_eq!(*group_name, "Dogs");
+                assert!(message.starts_with("Woof "), "{message}");
+            }
+            other => panic!("expected a message, got {other:?}"),
+        }
+
+        north
+            .wait_for(|metrics| metrics["messages_from_peers"].as_u64().unwrap() >= 1)
+            .await;
+        assert_eq!(north.metrics().await["messages_posted"], 0);
+        assert_eq!(south.metrics().await["messages_from_peers"], 0);
+
+        south.stop().await;
+        north.stop().await;
+    })
+}
+
+#[test]
+fn test_post_to_missing_group() {
+    rt::block_on(async {
+        let server = TestServer::start(&[]).await;
+        let (client, mut events) = server.connect().await;
+        client.post("Nowhere", "Hello?").await.unwrap();
+        match next_event(&mut events).await {
+            FromServer::Error(error) => assert!(error.contains("does not exist"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+
+        // The connection is still good.
+        client.join("Somewhere").await.unwrap();
+        client.post("Somewhere", "Hello!").await.unwrap();
+        assert_eq!(
+            next_event(&mut events).await,
+            message("Somewhere", 1, "Hello!")
+        );
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_bad_requests() {
+    rt::block_on(async {
+        let server = TestServer::start(&["--max-line-len", "100"]).await;
+
+        let replies = raw_exchange(&server, b"this is not json\n").await;
+        assert!(matches!(replies.as_slice(), [FromServer::Error(_)]));
+
+        let mut long_line = vec![b'x'; 200];
+        long_line.push(b'\n');
+        let replies = raw_exchange(&server, &long_line).await;
+        assert_eq!(
+            replies,
+            [FromServer::Error("line longer than 100 bytes".to_string())]
+        );
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_limits() {
+    rt::block_on(async {
+        let server = TestServer::start(&["--conn-rate", "0.1,2", "--max-connections", "1"]).await;
+        let (client, mut events) = server.connect().await;
+        client.join("Dogs").await.unwrap();
+        client.post("Dogs", "one").await.unwrap();
+        client.post("Dogs", "two").await.unwrap();
+        // The refusal and the group's message come by different routes, so
+        // either may arrive first.
+        let mut replies = vec![next_event(&mut events).await, next_event(&mut events).await];
+        let refusal = replies
+            .iter()
+            .position(|reply| matches!(reply, FromServer::Error(_)))
+            .expect("no rate limit error");
+        match replies.remove(refusal) {
+            FromServer::Error(error) => {
+                assert!(error.starts_with("Rate limit exceeded"), "{error}")
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(replies, [message("Dogs", 1, "one")]);
+
+        let replies = raw_exchange(&server, b"").await;
+        assert_eq!(
+            replies,
+            [FromServer::Error(
+                "Too many connections, try again later".to_string()
+            )]
+        );
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_join_refused() {
+    rt::block_on(async {
+        let server = TestServer::start(&["--conn-rate", "0.1,1"]).await;
+        let (client, mut events) = server.connect().await;
+        client.join("Dogs").await.unwrap();
+        client.join("Cats").await.unwrap();
+        match next_event(&mut events).await {
+            FromServer::JoinRefused { group_name, reason } => {
+                assert_eq!(*group_name, "Cats");
+                assert!(reason.starts_with("Rate limit exceeded"), "{reason}");
+            }
+            other => panic!("expected a refusal, got {other:?}"),
+        }
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_lagging_member() {
+    rt::block_on(async {
+        let server = TestServer::start(&[
+            "--conn-rate",
+            "1000000,1000000",
+            "--ip-rate",
+            "1000000,1000000",
+        ])
+        .await;
+        let (slow, mut slow_events) = server.connect().await;
+        slow.join("Dogs").await.unwrap();
+        server.wait_for_members("Dogs", 1).await;
+
+        // Post far more than the socket buffers and queues between the server
+        // and the slow member can hold, while it isn't reading.
+        let (poster, _poster_events) = server.connect().await;
+        let text = "x".repeat(1000);
+        for _ in 0..20_000 {
+            poster.post("Dogs", &text).await.unwrap();
+        }
+
+        let mut received = 0;
+        let dropped = loop {
+            match next_event(&mut slow_events).await {
+                FromServer::Message { .. } => received += 1,
+                FromServer::Error(error) => break error,
+                other => panic!("unexpected packet {other:?}"),
+            }
+        };
+        let count: u64 = dropped
+            .strip_prefix("Dropped ")
+            .and_then(|rest| rest.strip_suffix(" messages from Dogs."))
+            .and_then(|n| n.parse().ok())
+            .unwrap_or_else(|| panic!("unexpected error {dropped:?}"));
+        assert!(received > 0 && count > 0);
+        // The total also counts any drops reported since.
+        assert!(server.metrics().await["lag_drops"].as_u64().unwrap() >= count);
+
+        // The member hears the newest messages once it catches up.
+        assert!(matches!(
+            next_event(&mut slow_events).await,
+            FromServer::Message { .. }
+        ));
+
+        // Hang up rather than have shutdown wait for us to read the backlog.
+        drop((slow, slow_events));
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_disconnect_cleanup() {
+    rt::block_on(async {
+        let server = TestServer::start(&[]).await;
+        let (stays, mut stays_events) = server.connect().await;
+        let (leaves, leaves_events) = server.connect().await;
+        stays.join("Dogs").await.unwrap();
+        leaves.join("Dogs").await.unwrap();
+        server.wait_for_members("Dogs", 2).await;
+
+        drop((leaves, leaves_events));
+        server.wait_for_members("Dogs", 1).await;
+        server.wait_for_connections(1).await;
+
+        stays.post("Dogs", "Anyone?").await.unwrap();
+        assert_eq!(
+            next_event(&mut stays_events).await,
+            message("Dogs", 1, "Anyone?")
+        );
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_heartbeat() {
+    rt::block_on(async {
+        let server = TestServer::start(&["--heartbeat", "0.1", "--idle-timeout", "0.5"]).await;
+        // `ChatClient` answers pings, so it outlasts the idle timeout.
+        let (client, mut events) = server.connect().await;
+        client.join("Dogs").await.unwrap();
+
+        // A silent client gets pinged, then dropped once the idle timeout
+        // passes, with a parting word. Meanwhile the other client answers
+        // its pings, which it does only while its events are read.
+        let silent = async {
+            let socket = TcpStream::connect(&server.address.to_string())
+                .await
+                .unwrap();
+            let (reader, _writer) = socket.into_split();
+            let started = Instant::now();
+            let mut replies = utils::receive_as_json(BufReader::new(reader));
+            let first: FromServer = rt::timeout(PATIENCE, replies.next())
+                .await
+                .expect("no ping")
+                .unwrap()
+                .unwrap();
+            assert_eq!(first, FromServer::Ping);
+
+            let rest: Vec<FromServer> =
+                rt::timeout(PATIENCE, replies.map(Result::unwrap).collect())
+                    .await
+                    .expect("server didn't close the idle connection");
+            assert!(started.elapsed() >= Duration::from_millis(500));
+            match rest.last() {
+                Some(FromServer::Error(message)) => assert!(message.contains("Idle timeout")),
+                other => panic!("expected an idle timeout, got {other:?}"),
+            }
+            assert!(rest[..rest.len() - 1]
+                .iter()
+                .all(|packet| *packet == FromServer::Ping));
+        };
+        let answering = async {
+            let event = next_event(&mut events).await;
+            panic!("unexpected {event:?}");
+        };
+        silent.or(answering).await;
+        server.wait_for_connections(1).await;
+
+        // The client that answered is still connected.
+        client.post("Dogs", "Still here").await.unwrap();
+        assert_eq!(
+            next_event(&mut events).await,
+            message("Dogs", 1, "Still here")
+        );
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_admin_timeout() {
+    rt::block_on(async {
+        let server = TestServer::start(&["--heartbeat", "0.1", "--idle-timeout", "0.3"]).await;
+        // Connect, but never send a request.
+        let socket = TcpStream::connect(&server.admin_address.to_string())
+            .await
+            .unwrap();
+        let (mut reader, _writer) = socket.into_split();
+        let mut response = vec![];
+        rt::timeout(PATIENCE, reader.read_to_end(&mut response))
+            .await
+            .expect("admin endpoint kept a silent connection open")
+            .unwrap();
+        assert!(response.is_empty());
+
+        // It still answers everyone else.
+        server.wait_for_connections(0).await;
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_shutdown() {
+    rt::block_on(async {
+        let server = TestServer::start(&[]).await;
+        let (_client, mut events) = server.connect().await;
+        server.wait_for_connections(1).await;
+
+        let admin_address = server.admin_address.to_string();
+        server.stop().await;
+        assert!(matches!(
+            next_event(&mut events).await,
+            FromServer::Shutdown(_)
+        ));
+        assert!(events.next().await.is_none());
+
+        // The admin endpoint goes too, if not quite at once.
+        let deadline = Instant::now() + PATIENCE;
+        while TcpStream::connect(&admin_address).await.is_ok() {
+            assert!(Instant::now() < deadline, "admin endpoint still listening");
+            rt::sleep(Duration::from_millis(10)).await;
+        }
+    })
+}
+
+#[test]
+fn test_session_survives_reconnect() {
+    rt::block_on(async {
+        let server = TestServer::start(&[]).await;
+        let token = "0123456789abcdef0123";
+        let (author, mut author_events) = server.connect().await;
+        author.session(token).await.unwrap();
+        author.join("Dogs").await.unwrap();
+        author.post("Dogs", "Samoyeds rock!").await.unwrap();
+        assert_eq!(
+            next_event(&mut author_events).await,
+            message("Dogs", 1, "Samoyeds rock!")
+        );
+        drop(author);
+        drop(author_events);
+
+        // A new connection naming a different session can't touch it, nor
+        // can one that names none.
+        let (stranger, mut stranger_events) = server.connect().await;
+        stranger.session("fedcba9876543210fedc").await.unwrap();
+        stranger.join("Dogs").await.unwrap();
+        stranger.delete("Dogs", 1).await.unwrap();
+        match next_event(&mut stranger_events).await {
+            FromServer::Error(error) => assert!(error.contains("isn't yours"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+        let (anonymous, mut anonymous_events) = server.connect().await;
+        anonymous.delete("Dogs", 1).await.unwrap();
+        match next_event(&mut anonymous_events).await {
+            FromServer::Error(error) => assert!(error.contains("isn't yours"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+
+        // Reconnecting with the same session gets the message back.
+        let (author, mut author_events) = server.connect().await;
+        author.session(token).await.unwrap();
+        author.join("Dogs").await.unwrap();
+        server.wait_for_members("Dogs", 2).await;
+        author
+            .edit("Dogs", 1, "Samoyeds really rock!")
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut author_events).await,
+            FromServer::Edited {
+                group_name: Arc::new("Dogs".to_string()),
+                id: 1,
+                message: Arc::new("Samoyeds really rock!".to_string()),
+            }
+        );
+
+        // Tokens must be long, and can't be changed once set.
+        author.session("short").await.unwrap();
+        match next_event(&mut author_events).await {
+            FromServer::Error(error) => assert!(error.contains("already has a session"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+        anonymous.session("short").await.unwrap();
+        match next_event(&mut anonymous_events).await {
+            FromServer::Error(error) => assert!(error.contains("too short"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_edit_delete_react() {
+    rt::block_on(async {
+        let server = TestServer::start(&[]).await;
+        let (author, mut author_events) = server.connect().await;
+        let (reader, mut reader_events) = server.connect().await;
+        author.join("Dogs").await.unwrap();
+        reader.join("Dogs").await.unwrap();
+        server.wait_for_members("Dogs", 2).await;
+
+        author.post("Dogs", "Samoyeds rock!").await.unwrap();
+        for events in [&mut author_events, &mut reader_events] {
+            assert_eq!(
+                next_event(events).await,
+                message("Dogs", 1, "Samoyeds rock!")
+            );
+        }
+
+        reader.edit("Dogs", 1, "Cats rule!").await.unwrap();
+        match next_event(&mut reader_events).await {
+            FromServer::Error(error) => assert!(error.contains("isn't yours"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+
+        let dogs = Arc::new("Dogs".to_string());
+        author.edit("Dogs", 1, "Samoyeds rule!").await.unwrap();
+        reader.react("Dogs", 1, "+1").await.unwrap();
+        for events in [&mut author_events, &mut reader_events] {
+            let edited = FromServer::Edited {
+                group_name: dogs.clone(),
+                id: 1,
+                message: Arc::new("Samoyeds rule!".to_string()),
+            };
+            let reacted = FromServer::Reacted {
+                group_name: dogs.clone(),
+                id: 1,
+                reaction: Arc::new("+1".to_string()),
+            };
+            // The two requests came from different connections, so they may
+            // have reached the group in either order.
+            let mut replies = vec![next_event(events).await, next_event(events).await];
+            replies.sort_by_key(|reply| matches!(reply, FromServer::Reacted { .. }));
+            assert_eq!(replies, [edited, reacted]);
+        }
+
+        author.delete("Dogs", 1).await.unwrap();
+        for events in [&mut author_events, &mut reader_events] {
+            assert_eq!(
+                next_event(events).await,
+                FromServer::Deleted {
+                    group_name: dogs.clone(),
+                    id: 1
+                }
+            );
+        }
+        reader.react("Dogs", 1, "+1").await.unwrap();
+        match next_event(&mut reader_events).await {
+            FromServer::Error(error) => assert!(error.starts_with("No recent message"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_upload_buffer() {
+    rt::block_on(async {
+        let server =
+            TestServer::start(&["--max-attachment", "100000", "--upload-buffer", "100000"]).await;
+        let (client, mut events) = server.connect().await;
+        client.join("Logs").await.unwrap();
+        let start = |upload_id| FromClient::StartUpload {
+            upload_id,
+            group_name: Arc::new("Logs".to_string()),
+            file_name: Arc::new("big.bin".to_string()),
+            size: 60_000,
+        };
+
+        // Each is small enough alone, but the two together would hold more
+        // than the server will buffer.
+        client.send(&start(1)).await.unwrap();
+        assert_eq!(
+            next_event(&mut events).await,
+            FromServer::UploadStarted { upload_id: 1 }
+        );
+        client.send(&start(2)).await.unwrap();
+        match next_event(&mut events).await {
+            FromServer::UploadRefused {
+                upload_id: 2,
+                reason,
+            } => {
+                assert!(reason.contains("Too many uploads"), "{reason}")
+            }
+            other => panic!("expected a refusal, got {other:?}"),
+        }
+
+        // Once the first upload is abandoned, there's room again.
+        drop(client);
+        drop(events);
+        server.wait_for_connections(0).await;
+        let (client, mut events) = server.connect().await;
+        client.send(&start(3)).await.unwrap();
+        assert_eq!(
+            next_event(&mut events).await,
+            FromServer::UploadStarted { upload_id: 3 }
+        );
+
+        server.stop().await;
+    })
+}
+
+#[test]
+fn test_attachments() {
+    rt::block_on(async {
+        let server = TestServer::start(&["--max-attachment", "100000"]).await;
+        let (uploader, mut uploader_events) = server.connect().await;
+        let (member, mut member_events) = server.connect().await;
+        member.join("Logs").await.unwrap();
+        server.wait_for_members("Logs", 1).await;
+
+        // Several chunks' worth, not a multiple of the chunk size.
+        let data: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+        let upload_id = upload(&uploader, &mut uploader_events, "Logs", "build.log", &data)
+            .await
+            .unwrap();
+        let attachment_id = match next_event(&mut uploader_events).await {
+            FromServer::Uploaded {
+                upload_id: uploaded,
+                attachment_id,
+            } if uploaded == upload_id => attachment_id,
+            other => panic!("expected Uploaded, got {other:?}"),
+        };
+        assert_eq!(
+            next_event(&mut member_events).await,
+            FromServer::Attachment {
+                group_name: Arc::new("Logs".to_string()),
+                attachment_id,
+                file_name: Arc::new("build.log".to_string()),
+                size: data.len() as u64,
+                sha256: utils::sha256_hex(&data),
+            }
+        );
+
+        member.download(attachment_id).await.unwrap();
+        let mut download = Download::default();
+        let downloaded = loop {
+            match next_event(&mut member_events).await {
+                FromServer::DownloadChunk {
+                    attachment_id: id,
+                    offset,
+                    size,
+                    data,
+                } if id == attachment_id => {
+                    if let Some(whole) = download.add(offset, size, &data).unwrap() {
+                        break whole;
+                    }
+                }
+                other => panic!("expected a chunk, got {other:?}"),
+            }
+        };
+        assert_eq!(downloaded, data);
+
+        // Too big, so refused before any of it is sent, and no such
+        // attachment.
+        let huge = vec![0; 100_001];
+        let refused = upload(&uploader, &mut uploader_events, "Logs", "huge.bin", &huge).await;
+        let error = refused.unwrap_err().to_string();
+        assert!(error.contains("limited to 100000"), "{error}");
+        drop(uploader_events);
+
+        member.download(attachment_id + 1).await.unwrap();
+        match next_event(&mut member_events).await {
+            FromServer::Error(error) => assert!(error.starts_with("No attachment"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+
+        // Someone outside the group can't fetch it, even knowing its id.
+        let (outsider, mut outsider_events) = server.connect().await;
+        outsider.join("Other").await.unwrap();
+        outsider.download(attachment_id).await.unwrap();
+        match next_event(&mut outsider_events).await {
+            FromServer::Error(error) => assert!(error.starts_with("No attachment"), "{error}"),
+            other => panic!("expected an error, got {other:?}"),
+        }
+
+        server.stop().await;
+    })
+}
@@ -0,0 +1,300 @@
+//! A bounded pool of threads for blocking work, and the `spawn_blocking` that
+//! hands closures to it.
+//!
+//! The first version of `spawn_blocking` (see `main.rs`) started a thread for
+//! every closure. Here a pool starts threads only as work arrives, never more
+//! than its limit, and keeps them around for the next closure; anything beyond
+//! the limit waits in a queue.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+/// The most threads the pool behind the free `spawn_blocking` will start.
+const MAX_BLOCKING_THREADS: usize = 32;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct ThreadPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    /// Signalled when a job is queued or the pool shuts down.
+    work_ready: Condvar,
+    max_threads: usize,
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    /// Threads waiting on `work_ready`.
+    idle: usize,
+    shutdown: bool,
+}
+
+impl ThreadPool {
+    /// A pool that runs at most `max_threads` closures at once.
+    pub fn new(max_threads: usize) -> ThreadPool {
+        assert!(max_threads > 0, "a thread pool needs at least one thread");
+        ThreadPool {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                    shutdown: false,
+                }),
+                work_ready: Condvar::new(),
+                max_threads,
+            }),
+        }
+    }
+
+    /// Run `closure` on one of the pool's threads, returning a future of its
+    /// value. If the closure panics, so does whoever awaits the future.
+    pub fn spawn_blocking<T, F>(&self, closure: F) -> SpawnBlocking<T>
+    where
+        F: FnOnce() -> T,
+        F: Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = Arc::new(Mutex::new(Shared {
+            value: None,
+            waker: None,
+        }));
+
+        self.execute(Box::new({
+            let inner = inner.clone();
+            move || {
+                let value = panic::catch_unwind(AssertUnwindSafe(closure));
+
+                let maybe_waker = {
+                    let mut guard = inner.lock().unwrap();
+                    guard.value = Some(value);
+                    guard.waker.take()
+                };
+
+                if let Some(waker) = maybe_waker {
+                    waker.wake();
+                }
+            }
+        }));
+
+        SpawnBlocking(inner)
+    }
+
+    /// How many threads the pool has started so far.
+    pub fn thread_count(&self) -> usize {
+        self.inner.state.lock().unwrap().threads
+    }
+
+    fn execute(&self, job: Job) {
+        let mut state = self.inner.state.lock().unwrap();
+        match state.admit(job, self.inner.max_threads) {
+            Admitted::WakeIdle => self.inner.work_ready.notify_one(),
+            Admitted::StartThread => {
+                let inner = self.inner.clone();
+                thread::spawn(move || inner.work());
+            }
+            Admitted::Queued => {}
+        }
+    }
+}
+
+/// What to do so that a newly queued job gets run.
+#[derive(Debug, PartialEq)]
+enum Admitted {
+    WakeIdle,
+    StartThread,
+    /// Every thread is busy, and one will get to it.
+    Queued,
+}
+
+impl State {
+    fn admit(&mut self, job: Job, max_threads: usize) -> Admitted {
+        self.queue.push_back(job);
+        // An idle thread only stops counting as idle once it wakes, so in a
+        // burst the same one would be woken for every job. Wake one only
+        // while there are more idle threads than jobs waiting for them.
+        if self.queue.len() <= self.idle {
+            Admitted::WakeIdle
+        } else if self.threads < max_threads {
+            self.threads += 1;
+            Admitted::StartThread
+        } else {
+            Admitted::Queued
+        }
+    }
+}
+
+impl Inner {
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                // Jobs catch their own panics, so this thread carries on.
+                job();
+                state = self.state.lock().unwrap();
+            } else if state.shutdown {
+                state.threads -= 1;
+                return;
+            } else {
+                state.idle += 1;
+                state = self.work_ready.wait(state).unwrap();
+                state.idle -= 1;
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Let the threads exit once they've finished whatever is still queued.
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().shutdown = true;
+        self.inner.work_ready.notify_all();
+    }
+}
+
+pub struct SpawnBlocking<T>(Arc<Mutex<Shared<T>>>);
+
+struct Shared<T> {
+    value: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T: Send> Future for SpawnBlocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut guard = self.0.lock().unwrap();
+        match guard.value.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                guard.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Run `closure` on a shared pool of up to `MAX_BLOCKING_THREADS` threads.
+pub fn spawn_blocking<T, F>(closure: F) -> SpawnBlocking<T>
+where
+    F: FnOnce() -> T,
+    F: Send + 'static,
+    T: Send + 'static,
+{
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| ThreadPool::new(MAX_BLOCKING_THREADS))
+        .spawn_blocking(closure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::block_on;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let pool = ThreadPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+
+        let futures: Vec<_> = (0..8)
+            .map(|i| {
+                let running = running.clone();
+                let most_running = most_running.clone();
+                pool.spawn_blocking(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    i * 10
+                })
+            })
+            .collect();
+
+        let results: Vec<usize> = futures.into_iter().map(block_on).collect();
+        assert_eq!(results, vec![0, 10, 20, 30, 40, 50, 60, 70]);
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.thread_count(), 2);
+    }
+
+    #[test]
+    fn test_admit_burst() {
+        // One thread idle, and four jobs arriving before it wakes.
+        let mut state = State {
+            queue: VecDeque::new(),
+            threads: 1,
+            idle: 1,
+            shutdown: false,
+        };
+        let admitted: Vec<_> = (0..5).map(|_| state.admit(Box::new(|| {}), 4)).collect();
+        assert_eq!(
+            admitted,
+            [
+                Admitted::WakeIdle,
+                Admitted::StartThread,
+                Admitted::StartThread,
+                Admitted::StartThread,
+                Admitted::Queued,
+            ]
+        );
+        assert_eq!(state.threads, 4);
+    }
+
+    #[test]
+    fn test_burst_runs_in_parallel() {
+        let pool = ThreadPool::new(4);
+        // Leave one thread idle, waiting for work.
+        assert_eq!(block_on(pool.spawn_blocking(|| 1)), 1);
+        while pool.inner.state.lock().unwrap().idle == 0 {
+            thread::yield_now();
+        }
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let futures: Vec<_> = (0..4)
+            .map(|_| {
+                let running = running.clone();
+                let most_running = most_running.clone();
+                pool.spawn_blocking(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        futures.into_iter().for_each(block_on);
+        assert_eq!(pool.thread_count(), 4);
+        assert_eq!(most_running.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_panic_reaches_awaiter() {
+        let pool = ThreadPool::new(1);
+        let failed = pool.spawn_blocking(|| -> u32 { panic!("deliberate") });
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| block_on(failed)));
+        assert!(outcome.is_err());
+
+        // The pool's only thread survived.
+        assert_eq!(block_on(pool.spawn_blocking(|| 7)), 7);
+        assert_eq!(pool.thread_count(), 1);
+    }
+}
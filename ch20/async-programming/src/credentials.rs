@@ -0,0 +1,447 @@
+//! Password hashing and checking as a service, for any binary that needs to
+//! keep user accounts.
+//!
+//! `verify_password` in `main.rs` handed each check to `spawn_blocking`, which
+//! is right for work this heavy, but nothing stopped a rush of logins from
+//! starting a thread each. Here every hash and check runs on a pool with one
+//! thread per CPU; anything more waits its turn in the pool's queue.
+
+use std::{
+    collections::BTreeMap,
+    error, fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use argonautica::{config::Variant, Hasher, Verifier};
+
+use crate::blocking::ThreadPool;
+
+#[derive(Debug)]
+pub enum Error {
+    Hash(argonautica::Error),
+    Io(io::Error),
+    /// A line of the user file we couldn't make sense of.
+    BadStore(String),
+    BadUserName(String),
+    UserExists(String),
+    UnknownUser(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Hash(error) => write!(f, "hashing failed: {}", error),
+            Error::Io(error) => write!(f, "{}", error),
+            Error::BadStore(line) => write!(f, "bad line in user file: {}", line),
+            Error::BadUserName(name) => {
+                write!(f, "user names can't contain ':' or newlines: {:?}", name)
+            }
+            Error::UserExists(name) => write!(f, "user {} already exists", name),
+            Error::UnknownUser(name) => write!(f, "no user called {}", name),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// How much work each hash takes. Raising these makes guessing passwords
+/// from a stolen user file slower, and logging in too.
+#[derive(Clone, Debug)]
+pub struct HashParams {
+    pub iterations: u32,
+    /// In KiB.
+    pub memory_size: u32,
+    pub lanes: u32,
+    /// In bytes.
+    pub hash_len: u32,
+}
+
+impl Default for HashParams {
+    /// argonautica's defaults, except for a single lane: our parallelism
+    /// comes from hashing many passwords at once, not from splitting one.
+    fn default() -> Self {
+        HashParams {
+            iterations: 192,
+            memory_size: 4096,
+            lanes: 1,
+            hash_len: 32,
+        }
+    }
+}
+
+pub struct Credentials {
+    pool: ThreadPool,
+    params: HashParams,
+    secret_key: Arc<String>,
+    /// Shared with the pool, which does the saving.
+    users: Arc<UserStore>,
+}
+
+impl Credentials {
+    /// A service hashing with `params` and `secret_key`, keeping accounts in
+    /// `users`. The key must be the same one the stored hashes were made with.
+    pub fn new(secret_key: &str, params: HashParams, users: UserStore) -> Credentials {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        Credentials {
+            pool: ThreadPool::new(cpus),
+            params,
+            secret_key: Arc::new(secret_key.to_string()),
+            users: Arc::new(users),
+        }
+    }
+
+    pub async fn hash_password(&self, password: &str) -> Result<String> {
+        let password = password.to_string();
+        let secret_key = self.secret_key.to_string();
+        let params = self.params.clone();
+        self.pool
+            .spawn_blocking(move || {
+                Hasher::default()
+                    .configure_iterations(params.iterations)
+                    .configure_memory_size(params.memory_size)
+                    .configure_lanes(params.lanes)
+                    .configure_threads(1)
+                    .configure_hash_len(params.hash_len)
+                    .configure_variant(Variant::Argon2id)
+                    .with_password(password)
+                    .with_secret_key(secret_key)
+                    .hash()
+            })
+            .await
+            .map_err(Error::Hash)
+    }
+
+    /// Check `password` against `hash`. The parameters come from the hash
+    /// itself, so hashes made before they were changed still check out.
+    pub async fn verify_password(&self, password: &str, hash: &str) -> Result<bool> {
+        // クロージャを'staticにするために引数のコピーを作る
+        let password = password.to_string();
+        let hash = hash.to_string();
+        let secret_key = self.secret_key.to_string();
+        self.pool
+            .spawn_blocking(move || {
+                Verifier::default()
+                    .configure_threads(1)
+                    .with_hash(hash)
+                    .with_password(password)
+                    .with_secret_key(secret_key)
+                    .verify()
+            })
+            .await
+            .map_err(Error::Hash)
+    }
+
+    pub async fn add_user(&self, name: &str, password: &str) -> Result<()> {
+        check_user_name(name)?;
+        if self.users.get(name).is_some() {
+            return Err(Error::UserExists(name.to_string()));
+        }
+        let hash = self.hash_password(password).await?;
+        // Someone else may have taken the name while we were hashing.
+        self.insert_user(name, hash, false).await
+    }
+
+    pub async fn change_password(&self, name: &str, new_password: &str) -> Result<()> {
+        if self.users.get(name).is_none() {
+            return Err(Error::UnknownUser(name.to_string()));
+        }
+        let hash = self.hash_password(new_password).await?;
+        self.insert_user(name, hash, true).await
+    }
+
+    /// Saving the user file waits on the disk, so it happens on the pool.
+    async fn insert_user(&self, name: &str, hash: String, replace: bool) -> Result<()> {
+        let users = self.users.clone();
+        let name = name.to_string();
+        self.pool
+            .spawn_blocking(move || users.insert(&name, hash, replace))
+            .await
+    }
+
+    /// Whether `password` is `name`'s password. Unknown users are refused
+    /// only after as much work as a real check, so that timing doesn't give
+    /// away which names exist.
+    pub async fn check(&self, name: &str, password: &str) -> Result<bool> {
+        match self.users.get(name) {
+            Some(hash) => self.verify_password(password, &hash).await,
+            None => {
+                self.hash_password(password).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn users(&self) -> &UserStore {
+        &self.users
+    }
+}
+
+fn check_user_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains([':', '\n', '\r']) {
+        return Err(Error::BadUserName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// User names and their password hashes, kept in a file with one
+/// `name:hash` line per user.
+pub struct UserStore {
+    path: PathBuf,
+    users: Mutex<BTreeMap<String, String>>,
+    /// Held while the file is written, so that concurrent changes land in
+    /// order, without making readers of `users` wait for the disk.
+    saving: Mutex<()>,
+}
+
+impl UserStore {
+    /// Load the users in `path`. A missing file is an empty store, created
+    /// when the first user is added.
+    pub fn open(path: impl AsRef<Path>) -> Result<UserStore> {
+        let path = path.as_ref().to_path_buf();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut users = BTreeMap::new();
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            match line.split_once(':') {
+                Some((name, hash)) if !name.is_empty() && !hash.is_empty() => {
+                    users.insert(name.to_string(), hash.to_string());
+                }
+                _ => return Err(Error::BadStore(line.to_string())),
+            }
+        }
+
+        Ok(UserStore {
+            path,
+            users: Mutex::new(users),
+            saving: Mutex::new(()),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.users.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.users.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Add or replace a user, and save the file. This blocks on the disk.
+    fn insert(&self, name: &str, hash: String, replace: bool) -> Result<()> {
+        let _saving = self.saving.lock().unwrap();
+        let mut updated = self.users.lock().unwrap().clone();
+        if !replace && updated.contains_key(name) {
+            return Err(Error::UserExists(name.to_string()));
+        }
+        updated.insert(name.to_string(), hash);
+
+        // The change shows only once it's on disk.
+        self.save(&updated)?;
+        *self.users.lock().unwrap() = updated;
+        Ok(())
+    }
+
+    /// Write the whole file afresh, then move it into place, so a crash
+    /// part way through leaves the old file intact. The new file must reach
+    /// the disk before the rename does, or a crash could leave it empty, and
+    /// the rename isn't durable until its directory is synced too.
+    fn save(&self, users: &BTreeMap<String, String>) -> Result<()> {
+        let mut contents = String::new();
+        for (name, hash) in users {
+            contents.push_str(&format!("{}:{}\n", name, hash));
+        }
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        // A leftover file would keep its permissions, whatever we ask for.
+        match fs::remove_file(&temporary) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // The hashes are for our eyes only.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temporary)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temporary, &self.path)?;
+
+        #[cfg(unix)]
+        {
+            let directory = match self.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            fs::File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::block_on;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    /// Cheap enough that the tests don't crawl.
+    fn quick() -> HashParams {
+        HashParams {
+            iterations: 1,
+            memory_size: 64,
+            lanes: 1,
+            hash_len: 32,
+        }
+    }
+
+    fn scratch_file() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "credentials-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = std::env::temp_dir().join(name);
+        let _ignored = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        let store = UserStore::open(scratch_file()).unwrap();
+        let credentials = Credentials::new("pepper", quick(), store);
+        block_on(async {
+            let hash = credentials.hash_password("hunter2").await.unwrap();
+            assert!(credentials.verify_password("hunter2", &hash).await.unwrap());
+            assert!(!credentials.verify_password("hunter3", &hash).await.unwrap());
+
+            // Each hash gets its own salt.
+            let again = credentials.hash_password("hunter2").await.unwrap();
+            assert_ne!(hash, again);
+
+            // Hashes made with other parameters still verify.
+            let stronger = HashParams {
+                iterations: 2,
+                ..quick()
+            };
+            let other =
+                Credentials::new("pepper", stronger, UserStore::open(scratch_file()).unwrap());
+            let strong_hash = other.hash_password("hunter2").await.unwrap();
+            assert!(credentials
+                .verify_password("hunter2", &strong_hash)
+                .await
+                .unwrap());
+
+            // But not with a different secret key.
+            let wrong_key =
+                Credentials::new("salt", quick(), UserStore::open(scratch_file()).unwrap());
+            assert!(!wrong_key
+                .verify_password("hunter2", &hash)
+                .await
+                .unwrap_or(false));
+        });
+    }
+
+    #[test]
+    fn test_pool_uses_every_cpu() {
+        let credentials =
+            Credentials::new("pepper", quick(), UserStore::open(scratch_file()).unwrap());
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+
+        // Hash once, and give the thread that did it time to go idle.
+        block_on(async {
+            let hash = credentials.hash_password("hunter2").await.unwrap();
+            assert!(credentials.verify_password("hunter2", &hash).await.unwrap());
+        });
+        thread::sleep(Duration::from_millis(20));
+
+        // A burst of one job per CPU, like a rush of logins, runs all at once.
+        let jobs: Vec<_> = (0..cpus)
+            .map(|_| {
+                let running = running.clone();
+                let most_running = most_running.clone();
+                credentials.pool.spawn_blocking(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        jobs.into_iter().for_each(block_on);
+        assert_eq!(most_running.load(Ordering::SeqCst), cpus);
+        assert_eq!(credentials.pool.thread_count(), cpus);
+    }
+
+    #[test]
+    fn test_user_store() {
+        let path = scratch_file();
+        let credentials = Credentials::new("pepper", quick(), UserStore::open(&path).unwrap());
+        block_on(async {
+            credentials.add_user("jimb", "rust").await.unwrap();
+            credentials
+                .add_user("jorendorff", "fearless")
+                .await
+                .unwrap();
+            assert!(matches!(
+                credentials.add_user("jimb", "again").await,
+                Err(Error::UserExists(_))
+            ));
+            assert!(matches!(
+                credentials.add_user("a:b", "x").await,
+                Err(Error::BadUserName(_))
+            ));
+
+            assert!(credentials.check("jimb", "rust").await.unwrap());
+            assert!(!credentials.check("jimb", "fearless").await.unwrap());
+            assert!(!credentials.check("nobody", "rust").await.unwrap());
+
+            credentials.change_password("jimb", "ferris").await.unwrap();
+            assert!(matches!(
+                credentials.change_password("nobody", "x").await,
+                Err(Error::UnknownUser(_))
+            ));
+        });
+
+        // Another process opening the same file sees the same users.
+        let reopened = Credentials::new("pepper", quick(), UserStore::open(&path).unwrap());
+        assert_eq!(reopened.users().names(), vec!["jimb", "jorendorff"]);
+        block_on(async {
+            assert!(reopened.check("jimb", "ferris").await.unwrap());
+            assert!(!reopened.check("jimb", "rust").await.unwrap());
+        });
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::write(&path, "no colon here\n").unwrap();
+        assert!(matches!(UserStore::open(&path), Err(Error::BadStore(_))));
+        fs::remove_file(&path).unwrap();
+    }
+}
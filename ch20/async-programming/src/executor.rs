@@ -0,0 +1,324 @@
+//! An executor that runs many tasks on one thread, grown out of the
+//! single-future `block_on` in `main.rs`.
+//!
+//! As before, the thread parks whenever nothing can make progress and wakers
+//! unpark it. Each spawned task's waker now also puts the task back on a
+//! ready queue, so that `block_on` polls only the tasks that were woken.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use crossbeam::sync::{Parker, Unparker};
+use futures_lite::{pin, FutureExt};
+use waker_fn::waker_fn;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Runs spawned tasks while `block_on` is driving some future. Tasks not
+/// finished when `block_on` returns carry on at the next call.
+pub struct Executor {
+    parker: Parker,
+    spawner: Spawner,
+}
+
+/// Spawns tasks onto an `Executor` from anywhere, including its own tasks.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    ready: Mutex<VecDeque<Arc<Task>>>,
+    unparker: Unparker,
+}
+
+struct Task {
+    /// `None` once the task has finished.
+    future: Mutex<Option<BoxFuture>>,
+    /// Whether the task is already on the ready queue, so that waking it
+    /// several times before it runs queues it once.
+    scheduled: AtomicBool,
+    /// Weak, since the ready queue holds tasks: a strong reference would
+    /// keep queued tasks and their executor alive after it was dropped.
+    shared: Weak<Shared>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        let parker = Parker::new();
+        let unparker = parker.unparker().clone();
+        Executor {
+            parker,
+            spawner: Spawner {
+                shared: Arc::new(Shared {
+                    ready: Mutex::new(VecDeque::new()),
+                    unparker,
+                }),
+            },
+        }
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawner.spawn(future)
+    }
+
+    /// Run spawned tasks until `future` is ready, returning its value.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let woken = Arc::new(AtomicBool::new(true));
+        let waker = waker_fn({
+            let woken = woken.clone();
+            let unparker = self.spawner.shared.unparker.clone();
+            move || {
+                woken.store(true, Ordering::Release);
+                unparker.unpark();
+            }
+        });
+        let mut context = Context::from_waker(&waker);
+        pin!(future);
+
+        loop {
+            if woken.swap(false, Ordering::AcqRel) {
+                if let Poll::Ready(value) = future.as_mut().poll(&mut context) {
+                    return value;
+                }
+            }
+
+            let next = self.spawner.shared.ready.lock().unwrap().pop_front();
+            match next {
+                Some(task) => task.run(),
+                // An unpark that came in since we last looked makes this
+                // return at once, so no wakeup is lost.
+                None if !woken.load(Ordering::Acquire) => self.parker.park(),
+                None => {}
+            }
+        }
+    }
+}
+
+impl Spawner {
+    /// Start running `future` as a task of its own. A panic in the task ends
+    /// only that task, and resurfaces in whoever awaits its `JoinHandle`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let join = Arc::new(Mutex::new(JoinState {
+            result: None,
+            waker: None,
+        }));
+
+        let wrapped = {
+            let join = join.clone();
+            async move {
+                let result = AssertUnwindSafe(future).catch_unwind().await;
+                let maybe_waker = {
+                    let mut guard = join.lock().unwrap();
+                    guard.result = Some(result);
+                    guard.waker.take()
+                };
+                if let Some(waker) = maybe_waker {
+                    waker.wake();
+                }
+            }
+        };
+
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(wrapped))),
+            scheduled: AtomicBool::new(false),
+            shared: Arc::downgrade(&self.shared),
+        });
+        task.schedule();
+        JoinHandle(join)
+    }
+}
+
+impl Task {
+    fn schedule(self: &Arc<Self>) {
+        // Once the executor and its spawners are gone, nothing will run us.
+        let Some(shared) = self.shared.upgrade() else {
+            return;
+        };
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            shared.ready.lock().unwrap().push_back(self.clone());
+            shared.unparker.unpark();
+        }
+    }
+
+    fn run(self: Arc<Self>) {
+        // Clear the flag first, so a wakeup during the poll queues us again.
+        self.scheduled.store(false, Ordering::Release);
+        let waker = waker_fn({
+            let task = self.clone();
+            move || task.schedule()
+        });
+        let mut context = Context::from_waker(&waker);
+
+        let mut slot = self.future.lock().unwrap();
+        if let Some(future) = slot.as_mut() {
+            if future.as_mut().poll(&mut context).is_ready() {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// A spawned task's result. Dropping the handle leaves the task running.
+pub struct JoinHandle<T>(Arc<Mutex<JoinState<T>>>);
+
+struct JoinState<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut guard = self.0.lock().unwrap();
+        match guard.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                guard.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Drive a single future to completion on this thread, as in `main.rs`.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    // parkerは対応するunparkerが呼び出されるまでスレッドをブロックする
+    let parker = Parker::new();
+    let unparker = parker.unparker().clone();
+    // unparkするwakerとそれを持つContext
+    let waker = waker_fn(move || unparker.unpark());
+    let mut context = Context::from_waker(&waker);
+
+    // FutureのPinを作る
+    pin!(future);
+
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(value) => return value,
+            Poll::Pending => parker.park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::spawn_blocking;
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    #[test]
+    fn test_tasks_woken_from_other_threads() {
+        let executor = Executor::new();
+        let handles: Vec<_> = (0..20u64)
+            .map(|i| {
+                executor.spawn(async move {
+                    let doubled = spawn_blocking(move || {
+                        thread::sleep(Duration::from_millis(20 - i));
+                        i * 2
+                    })
+                    .await;
+                    doubled + 1
+                })
+            })
+            .collect();
+
+        let results = executor.block_on(async {
+            let mut results = vec![];
+            for handle in handles {
+                results.push(handle.await);
+            }
+            results
+        });
+        assert_eq!(results, (0..20).map(|i| i * 2 + 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_tasks_spawn_tasks() {
+        let executor = Executor::new();
+        let spawner = executor.spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let outer = executor.spawn({
+            let count = count.clone();
+            async move {
+                let inner: Vec<_> = (0..10)
+                    .map(|_| {
+                        let count = count.clone();
+                        spawner.spawn(async move {
+                            count.fetch_add(1, Ordering::SeqCst);
+                        })
+                    })
+                    .collect();
+                for handle in inner {
+                    handle.await;
+                }
+            }
+        });
+        executor.block_on(outer);
+        assert_eq!(count.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_panicking_task() {
+        let executor = Executor::new();
+        let failed = executor.spawn(async {
+            spawn_blocking(|| ()).await;
+            panic!("deliberate");
+        });
+        let fine = executor.spawn(async { spawn_blocking(|| "fine").await });
+
+        // The other task still runs, and the panic shows up in the awaiter.
+        assert_eq!(executor.block_on(fine), "fine");
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| executor.block_on(failed)));
+        assert!(outcome.is_err());
+
+        assert_eq!(executor.block_on(executor.spawn(async { 42 })), 42);
+    }
+
+    #[test]
+    fn test_dropping_executor_drops_tasks() {
+        let executor = Executor::new();
+        let resource = Arc::new(());
+        let held = resource.clone();
+        executor.spawn(async move {
+            let _held = held;
+            futures_lite::future::pending::<()>().await;
+        });
+
+        // The task is still queued, never having run; dropping the executor
+        // must drop it too.
+        drop(executor);
+        assert_eq!(Arc::strong_count(&resource), 1);
+    }
+}
@@ -0,0 +1,326 @@
+//! Running one operation over many inputs, a few at a time.
+//!
+//! `many_requests3` in `main.rs` spawns a task per URL all at once, so ten
+//! thousand URLs means ten thousand open sockets, and a request that fails is
+//! simply reported. `FanOut` spawns a task only once a semaphore permit frees
+//! up, retries failures that might go away on their own, and returns what
+//! happened to each input in the order the inputs came.
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    executor::Spawner,
+    http::{self, Client, Response},
+    semaphore::Semaphore,
+    timer,
+};
+
+/// Errors that might not happen again if we try once more.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for http::Error {
+    fn is_transient(&self) -> bool {
+        matches!(self, http::Error::Io(_) | http::Error::TimedOut)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in all, the first included.
+    pub max_attempts: u32,
+    /// The wait before the first retry, doubled for each retry after.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What became of one input.
+#[derive(Debug)]
+pub struct Outcome<T, E> {
+    /// The last attempt's result.
+    pub result: Result<T, E>,
+    pub attempts: u32,
+    /// How long the input waited for a free slot before its first attempt.
+    pub queued: Duration,
+    /// From the first attempt to the last result, waits between retries
+    /// included.
+    pub elapsed: Duration,
+}
+
+pub struct FanOut {
+    concurrency: usize,
+    retry: RetryPolicy,
+}
+
+impl FanOut {
+    /// Run at most `concurrency` operations at once, retrying as
+    /// `RetryPolicy::default()` says.
+    pub fn new(concurrency: usize) -> FanOut {
+        assert!(concurrency > 0, "a fan-out needs room for one operation");
+        FanOut {
+            concurrency,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> FanOut {
+        self.retry = retry;
+        self
+    }
+
+    /// Apply `operation` to every input on `spawner`'s executor, returning
+    /// the outcomes in input order. `operation` is called afresh for each
+    /// attempt.
+    pub async fn run<I, T, E, F, Fut>(
+        &self,
+        spawner: &Spawner,
+        inputs: impl IntoIterator<Item = I>,
+        operation: F,
+    ) -> Vec<Outcome<T, E>>
+    where
+        I: Send + Sync + 'static,
+        T: Send + 'static,
+        E: Transient + Send + 'static,
+        F: Fn(&I) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+    {
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let operation = Arc::new(operation);
+
+        let mut handles = vec![];
+        for input in inputs {
+            let waiting_since = Instant::now();
+            let permit = semaphore.acquire().await;
+            let queued = waiting_since.elapsed();
+
+            let operation = operation.clone();
+            let retry = self.retry.clone();
+            handles.push(spawner.spawn(async move {
+                // The permit is held through the waits between retries too,
+                // so retrying inputs don't crowd out the rest.
+                let mut outcome = with_retries(&retry, &input, &*operation).await;
+                drop(permit);
+                outcome.queued = queued;
+                outcome
+            }));
+        }
+
+        let mut outcomes = Vec::with_capacity(handles.len());
+        for handle in handles {
+            outcomes.push(handle.await);
+        }
+        outcomes
+    }
+
+    /// Fetch every URL with `client`.
+    pub async fn get_all(
+        &self,
+        spawner: &Spawner,
+        client: Arc<Client>,
+        urls: impl IntoIterator<Item = String>,
+    ) -> Vec<Outcome<Response, http::Error>> {
+        self.run(spawner, urls, move |url: &String| {
+            let client = client.clone();
+            let url = url.clone();
+            async move { client.get(&url).await }
+        })
+        .await
+    }
+}
+
+async fn with_retries<I, T, E, F, Fut>(
+    retry: &RetryPolicy,
+    input: &I,
+    operation: &F,
+) -> Outcome<T, E>
+where
+    E: Transient,
+    F: Fn(&I) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let start = Instant::now();
+    let mut backoff = retry.initial_backoff;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match operation(input).await {
+            Err(error) if error.is_transient() && attempts < retry.max_attempts => {
+                timer::sleep(jitter(backoff)).await;
+                backoff = (backoff * 2).min(retry.max_backoff);
+            }
+            result => {
+                return Outcome {
+                    result,
+                    attempts,
+                    queued: Duration::ZERO,
+                    elapsed: start.elapsed(),
+                }
+            }
+        }
+    }
+}
+
+/// Somewhere between half of `backoff` and all of it, so that inputs that
+/// failed together don't all retry together.
+fn jitter(backoff: Duration) -> Duration {
+    // Each `RandomState` is seeded differently, which is all the randomness
+    // we need.
+    let random = RandomState::new().build_hasher().finish();
+    let half = backoff / 2;
+    half + half.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum TestError {
+        Flaky,
+        Broken,
+    }
+
+    impl Transient for TestError {
+        fn is_transient(&self) -> bool {
+            *self == TestError::Flaky
+        }
+    }
+
+    fn quick_retries(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(2),
+            max_backoff: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn test_bounded_and_in_order() {
+        let executor = Executor::new();
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+
+        let operation = {
+            let running = running.clone();
+            let most_running = most_running.clone();
+            move |&i: &u64| {
+                let running = running.clone();
+                let most_running = most_running.clone();
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    // Later inputs finish sooner.
+                    timer::sleep(Duration::from_millis(20 - i)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, TestError>(i * i)
+                }
+            }
+        };
+
+        let fan_out = FanOut::new(3);
+        let outcomes = executor.block_on(fan_out.run(&executor.spawner(), 0..12, operation));
+        let squares: Vec<u64> = outcomes
+            .iter()
+            .map(|o| *o.result.as_ref().unwrap())
+            .collect();
+        assert_eq!(squares, (0..12).map(|i| i * i).collect::<Vec<_>>());
+        assert_eq!(most_running.load(Ordering::SeqCst), 3);
+        assert!(outcomes.iter().all(|o| o.attempts == 1));
+        assert!(outcomes[0].elapsed >= Duration::from_millis(20));
+        // The first three started at once; the last had to wait its turn.
+        assert!(outcomes[11].queued > outcomes[0].queued);
+    }
+
+    #[test]
+    fn test_retries() {
+        let executor = Executor::new();
+        let calls = Arc::new(Mutex::new(vec![0; 3]));
+
+        let operation = {
+            let calls = calls.clone();
+            move |&i: &usize| {
+                let call = {
+                    let mut calls = calls.lock().unwrap();
+                    calls[i] += 1;
+                    calls[i]
+                };
+                async move {
+                    match i {
+                        // Fails twice, then works.
+                        0 if call < 3 => Err(TestError::Flaky),
+                        0 => Ok("finally"),
+                        // Never works, but might.
+                        1 => Err(TestError::Flaky),
+                        // Never works, and never will.
+                        _ => Err(TestError::Broken),
+                    }
+                }
+            }
+        };
+
+        let fan_out = FanOut::new(2).with_retry(quick_retries(4));
+        let outcomes = executor.block_on(fan_out.run(&executor.spawner(), 0..3, operation));
+        assert_eq!(outcomes[0].result, Ok("finally"));
+        assert_eq!(outcomes[0].attempts, 3);
+        // Two waits of at least 1ms and 2ms.
+        assert!(outcomes[0].elapsed >= Duration::from_millis(3));
+        assert_eq!(outcomes[1].result, Err(TestError::Flaky));
+        assert_eq!(outcomes[1].attempts, 4);
+        assert_eq!(outcomes[2].result, Err(TestError::Broken));
+        assert_eq!(outcomes[2].attempts, 1);
+        assert_eq!(*calls.lock().unwrap(), vec![3, 4, 1]);
+    }
+
+    #[test]
+    fn test_get_all_retries_refused_connections() {
+        // Bind and drop a listener, for a port nobody is listening on.
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let urls = vec![format!("http://{address}/"), "ftp://nope/".to_string()];
+
+        let executor = Executor::new();
+        let fan_out = FanOut::new(4).with_retry(quick_retries(2));
+        let client = Arc::new(Client::new());
+        let outcomes = executor.block_on(fan_out.get_all(&executor.spawner(), client, urls));
+        assert!(matches!(outcomes[0].result, Err(http::Error::Io(_))));
+        assert_eq!(outcomes[0].attempts, 2);
+        assert!(matches!(
+            outcomes[1].result,
+            Err(http::Error::InvalidUrl(_))
+        ));
+        assert_eq!(outcomes[1].attempts, 1);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..100 {
+            let wait = jitter(backoff);
+            assert!(wait >= backoff / 2 && wait <= backoff);
+        }
+    }
+}
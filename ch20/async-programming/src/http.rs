@@ -0,0 +1,680 @@
+//! An HTTP/1.1 client, the grown-up version of `cheapo_request` in `main.rs`.
+//!
+//! `cheapo_request` shut down its half of the socket and read until the server
+//! hung up, handing back the status line and headers along with the body.
+//! `Client::get` parses the response instead, so it knows where the body ends,
+//! whether it's sent with `Content-Length` or chunked. That lets it keep the
+//! connection open for the next request to the same host. It also follows
+//! redirects, gives up on requests that take too long, and refuses bodies
+//! larger than it's willing to hold.
+//!
+//! Only plain `http://` URLs are supported.
+
+use std::{borrow::Cow, collections::HashMap, error, fmt, io, sync::Mutex, time::Duration};
+
+use async_std::{
+    io::{prelude::*, BufReader},
+    net::TcpStream,
+};
+
+use crate::timer;
+
+/// The longest status line or header line we'll read.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+/// Idle connections kept for reuse, per host.
+const MAX_IDLE_PER_HOST: usize = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidUrl(String),
+    Io(io::Error),
+    /// The server's response wasn't HTTP as we know it.
+    Protocol(String),
+    TooManyRedirects,
+    TimedOut,
+    /// The body was longer than the client's limit, in bytes.
+    BodyTooLarge(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidUrl(message) => write!(f, "invalid URL: {}", message),
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Protocol(message) => write!(f, "bad response: {}", message),
+            Error::TooManyRedirects => f.write_str("too many redirects"),
+            Error::TimedOut => f.write_str("request timed out"),
+            Error::BodyTooLarge(limit) => write!(f, "response body over {} bytes", limit),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+fn protocol_error<T>(message: impl Into<String>) -> Result<T> {
+    Err(Error::Protocol(message.into()))
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Where the response came from, after following any redirects.
+    pub url: String,
+}
+
+impl Response {
+    /// The first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+}
+
+fn find_header<'h>(headers: &'h [(String, String)], name: &str) -> Option<&'h str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+pub struct Client {
+    idle: Mutex<HashMap<Origin, Vec<Connection>>>,
+    timeout: Duration,
+    max_redirects: usize,
+    max_body_size: usize,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    /// A client that gives each request 30 seconds, follows up to 10
+    /// redirects, and accepts bodies of up to 16 MiB.
+    pub fn new() -> Client {
+        Client {
+            idle: Mutex::new(HashMap::new()),
+            timeout: Duration::from_secs(30),
+            max_redirects: 10,
+            max_body_size: 16 * 1024 * 1024,
+        }
+    }
+
+    /// How long a request may take, redirects included.
+    pub fn with_timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Client {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// The longest body to accept, in bytes. Anything longer fails with
+    /// `Error::BodyTooLarge` before we've allocated room for it.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Client {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response> {
+        let url = Url::parse(url)?;
+        match timer::timeout(self.follow(url), self.timeout).await {
+            Ok(result) => result,
+            Err(timer::TimedOut) => Err(Error::TimedOut),
+        }
+    }
+
+    async fn follow(&self, mut url: Url) -> Result<Response> {
+        for _ in 0..=self.max_redirects {
+            let response = self.fetch(&url).await?;
+            if !matches!(response.status, 301 | 302 | 303 | 307 | 308) {
+                return Ok(response);
+            }
+            let location = match response.header("Location") {
+                Some(location) => location,
+                None => return protocol_error("redirect without a Location"),
+            };
+            url = url.join(location)?;
+        }
+        Err(Error::TooManyRedirects)
+    }
+
+    async fn fetch(&self, url: &Url) -> Result<Response> {
+        let pooled = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(&url.origin)
+            .and_then(|idle| idle.pop());
+        if let Some(mut connection) = pooled {
+            match connection.exchange(url, self.max_body_size).await {
+                Ok((response, reusable)) => {
+                    if reusable {
+                        self.put_back(&url.origin, connection);
+                    }
+                    return Ok(response);
+                }
+                // The server may have closed the connection while it sat
+                // idle. A GET is safe to repeat, so try a fresh one.
+                Err(Error::Io(_)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        let mut connection = Connection::open(&url.origin).await?;
+        let (response, reusable) = connection.exchange(url, self.max_body_size).await?;
+        if reusable {
+            self.put_back(&url.origin, connection);
+        }
+        Ok(response)
+    }
+
+    fn put_back(&self, origin: &Origin, connection: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(origin.clone()).or_default();
+        if connections.len() < MAX_IDLE_PER_HOST {
+            connections.push(connection);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Origin {
+    /// A name or address; IPv6 addresses are kept without their brackets.
+    host: String,
+    port: u16,
+}
+
+#[derive(Debug, PartialEq)]
+struct Url {
+    origin: Origin,
+    /// Always starts with `/`, and may include a query.
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => {
+                return Err(Error::InvalidUrl(format!(
+                    "{url}: only http:// is supported"
+                )))
+            }
+        };
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        // An IPv6 address is bracketed, since it has colons of its own.
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, after)) => match after.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(Error::InvalidUrl(format!("{url}: bad port"))),
+                },
+                None => return Err(Error::InvalidUrl(format!("{url}: unclosed '['"))),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, _)) if host.contains(':') => {
+                    return Err(Error::InvalidUrl(format!(
+                        "{url}: IPv6 addresses must be bracketed"
+                    )))
+                }
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => return Err(Error::InvalidUrl(format!("{url}: bad port"))),
+            },
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(Error::InvalidUrl(format!("{url}: no host")));
+        }
+
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{path}")
+        };
+        Ok(Url {
+            origin: Origin {
+                host: host.to_string(),
+                port,
+            },
+            path,
+        })
+    }
+
+    /// Resolve a redirect's `Location` against this URL.
+    fn join(&self, location: &str) -> Result<Url> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        // Scheme-relative: another host, reached the same way.
+        if location.starts_with("//") {
+            return Url::parse(&format!("http:{location}"));
+        }
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let directory = match self.path.rfind('/') {
+                Some(index) => &self.path[..=index],
+                None => "/",
+            };
+            format!("{directory}{location}")
+        };
+        Ok(Url {
+            origin: self.origin.clone(),
+            path,
+        })
+    }
+
+    /// The `Host` header, which leaves out the default port.
+    fn host(&self) -> String {
+        let host = if self.origin.host.contains(':') {
+            format!("[{}]", self.origin.host)
+        } else {
+            self.origin.host.clone()
+        };
+        match self.origin.port {
+            80 => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}{}", self.host(), self.path)
+    }
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    async fn open(origin: &Origin) -> Result<Connection> {
+        let stream = TcpStream::connect((origin.host.as_str(), origin.port)).await?;
+        Ok(Connection {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Send a GET for `url` and read the response, saying too whether the
+    /// connection can carry another request. The body may be at most
+    /// `max_body` bytes long.
+    async fn exchange(&mut self, url: &Url, max_body: usize) -> Result<(Response, bool)> {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n\r\n",
+            url.path,
+            url.host()
+        );
+        let socket = self.stream.get_mut();
+        socket.write_all(request.as_bytes()).await?;
+        socket.flush().await?;
+
+        // Skip any interim responses, like `100 Continue`.
+        let (version, status, reason, headers) = loop {
+            let status_line = self.read_line().await?;
+            let (version, status, reason) = parse_status_line(&status_line)?;
+            let headers = self.read_headers().await?;
+            if !(100..200).contains(&status) {
+                break (version, status, reason, headers);
+            }
+        };
+
+        let connection = find_header(&headers, "Connection").unwrap_or_default();
+        let mut reusable = if version == "HTTP/1.0" {
+            connection.eq_ignore_ascii_case("keep-alive")
+        } else {
+            !connection.eq_ignore_ascii_case("close")
+        };
+
+        let chunked = find_header(&headers, "Transfer-Encoding")
+            .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+        let body = if status == 204 || status == 304 {
+            vec![]
+        } else if chunked {
+            self.read_chunked(max_body).await?
+        } else if let Some(length) = find_header(&headers, "Content-Length") {
+            let length: usize = match length.parse() {
+                Ok(length) => length,
+                Err(_) => return protocol_error(format!("bad Content-Length: {length}")),
+            };
+            if length > max_body {
+                return Err(Error::BodyTooLarge(max_body));
+            }
+            let mut body = vec![0; length];
+            self.stream.read_exact(&mut body).await?;
+            body
+        } else {
+            // The body runs until the server hangs up.
+            reusable = false;
+            let mut body = vec![];
+            // Read one byte more than we'll accept, to tell whether there was
+            // more.
+            (&mut self.stream)
+                .take(max_body as u64 + 1)
+                .read_to_end(&mut body)
+                .await?;
+            if body.len() > max_body {
+                return Err(Error::BodyTooLarge(max_body));
+            }
+            body
+        };
+
+        let response = Response {
+            status,
+            reason,
+            headers,
+            body,
+            url: url.to_string(),
+        };
+        Ok((response, reusable))
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = vec![];
+        (&mut self.stream)
+            .take(MAX_LINE_LEN)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if line.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        match line.strip_suffix(b"\r\n") {
+            Some(line) => match String::from_utf8(line.to_vec()) {
+                Ok(line) => Ok(line),
+                Err(_) => protocol_error("line isn't UTF-8"),
+            },
+            None => protocol_error("line too long, or cut off"),
+        }
+    }
+
+    async fn read_headers(&mut self) -> Result<Vec<(String, String)>> {
+        let mut headers = vec![];
+        loop {
+            let line = self.read_line().await?;
+            if line.is_empty() {
+                return Ok(headers);
+            }
+            if headers.len() == MAX_HEADERS {
+                return protocol_error("too many headers");
+            }
+            match line.split_once(':') {
+                Some((name, value)) => headers.push((name.to_string(), value.trim().to_string())),
+                None => return protocol_error(format!("bad header: {line}")),
+            }
+        }
+    }
+
+    async fn read_chunked(&mut self, max_body: usize) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        loop {
+            let line = self.read_line().await?;
+            // Ignore chunk extensions, after a `;`.
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = match usize::from_str_radix(size, 16) {
+                Ok(size) => size,
+                Err(_) => return protocol_error(format!("bad chunk size: {line}")),
+            };
+            if size == 0 {
+                // Trailers, which we don't use, then a blank line.
+                self.read_headers().await?;
+                return Ok(body);
+            }
+
+            let start = body.len();
+            let end = match start.checked_add(size) {
+                Some(end) if end <= max_body => end,
+                _ => return Err(Error::BodyTooLarge(max_body)),
+            };
+            body.resize(end, 0);
+            self.stream.read_exact(&mut body[start..]).await?;
+            if !self.read_line().await?.is_empty() {
+                return protocol_error("chunk longer than its size");
+            }
+        }
+    }
+}
+
+fn parse_status_line(line: &str) -> Result<(String, u16, String)> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts.next().and_then(|status| status.parse().ok());
+    match status {
+        Some(status) if version.starts_with("HTTP/1.") => Ok((
+            version.to_string(),
+            status,
+            parts.next().unwrap_or_default().to_string(),
+        )),
+        _ => protocol_error(format!("bad status line: {line}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::block_on;
+    use async_std::{net::TcpListener, task};
+    use futures_lite::StreamExt;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// A stand-in server on an ephemeral port. `respond` gets each request's
+    /// path and returns the raw response; returning `None` leaves the client
+    /// hanging. Returns the server's address and its count of connections.
+    fn stand_in(respond: fn(&str) -> Option<String>) -> (String, Arc<AtomicUsize>) {
+        let listener = task::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let count = connections.clone();
+        task::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                task::spawn(serve_connection(socket, respond));
+            }
+        });
+        (address, connections)
+    }
+
+    async fn serve_connection(mut socket: TcpStream, respond: fn(&str) -> Option<String>) {
+        let mut lines = BufReader::new(socket.clone()).lines();
+        while let Some(Ok(request_line)) = lines.next().await {
+            // Skip the headers.
+            while let Some(Ok(header)) = lines.next().await {
+                if header.is_empty() {
+                    break;
+                }
+            }
+
+            let path = request_line.split(' ').nth(1).unwrap_or_default();
+            let response = match respond(path) {
+                Some(response) => response,
+                None => futures_lite::future::pending().await,
+            };
+            socket.write_all(response.as_bytes()).await.unwrap();
+            if response.contains("Connection: close") || response.contains("X-Then: hang up") {
+                return;
+            }
+        }
+    }
+
+    fn routes(path: &str) -> Option<String> {
+        let response = match path {
+            "/hello" => "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Greeting: hi\r\n\r\nhello",
+            "/chunked" => {
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: yes\r\n\r\n"
+            }
+            "/bye" => "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nX-Then: hang up\r\n\r\nbye",
+            "/until-close" => "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nthe whole rest",
+            "/old" => {
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: /hello\r\nContent-Length: 0\r\n\r\n"
+            }
+            "/relative/old" => "HTTP/1.1 302 Found\r\nLocation: new\r\nContent-Length: 0\r\n\r\n",
+            "/relative/new" => "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nnew",
+            "/loop" => "HTTP/1.1 302 Found\r\nLocation: /loop\r\nContent-Length: 0\r\n\r\n",
+            "/huge" => "HTTP/1.1 200 OK\r\nContent-Length: 1000000000000\r\n\r\n",
+            "/huge-chunk" => {
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n"
+            }
+            "/slow" => return None,
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        };
+        Some(response.to_string())
+    }
+
+    #[test]
+    fn test_url_parse() {
+        let url = Url::parse("http://example.com:8080/a/b?c=d#e").unwrap();
+        assert_eq!(url.origin.host, "example.com");
+        assert_eq!(url.origin.port, 8080);
+        assert_eq!(url.path, "/a/b?c=d");
+        assert_eq!(
+            url.join("x").unwrap().to_string(),
+            "http://example.com:8080/a/x"
+        );
+        assert_eq!(url.join("/y").unwrap().path, "/y");
+
+        assert_eq!(Url::parse("http://example.com").unwrap().path, "/");
+        assert_eq!(Url::parse("http://example.com?q").unwrap().path, "/?q");
+        assert!(Url::parse("https://example.com/").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+        assert!(Url::parse("http://example.com:http/").is_err());
+
+        let other = url.join("//other.example/z?w").unwrap();
+        assert_eq!(other.origin.host, "other.example");
+        assert_eq!(other.origin.port, 80);
+        assert_eq!(other.path, "/z?w");
+
+        let v6 = Url::parse("http://[::1]:8080/p").unwrap();
+        assert_eq!(v6.origin.host, "::1");
+        assert_eq!(v6.origin.port, 8080);
+        assert_eq!(v6.to_string(), "http://[::1]:8080/p");
+        assert_eq!(Url::parse("http://[::1]").unwrap().host(), "[::1]");
+        assert!(Url::parse("http://::1/").is_err());
+        assert!(Url::parse("http://[::1/").is_err());
+        assert!(Url::parse("http://[::1]x/").is_err());
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let (address, connections) = stand_in(routes);
+        let client = Client::new();
+        block_on(async {
+            for _ in 0..3 {
+                let response = client.get(&format!("{address}/hello")).await.unwrap();
+                assert_eq!(response.status, 200);
+                assert_eq!(response.reason, "OK");
+                assert_eq!(response.header("x-greeting"), Some("hi"));
+                assert_eq!(response.text(), "hello");
+            }
+            let missing = client.get(&format!("{address}/nope")).await.unwrap();
+            assert_eq!(missing.status, 404);
+        });
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_bodies() {
+        let (address, connections) = stand_in(routes);
+        let client = Client::new();
+        block_on(async {
+            let chunked = client.get(&format!("{address}/chunked")).await.unwrap();
+            assert_eq!(chunked.text(), "hello, world");
+
+            let until_close = client.get(&format!("{address}/until-close")).await.unwrap();
+            assert_eq!(until_close.text(), "the whole rest");
+
+            // The server hung up after that, so this needs a new connection.
+            let bye = client.get(&format!("{address}/bye")).await.unwrap();
+            assert_eq!(bye.text(), "bye");
+
+            // This time the server hung up without saying so, and the client
+            // only finds out when it tries to reuse the connection.
+            let after = client.get(&format!("{address}/hello")).await.unwrap();
+            assert_eq!(after.text(), "hello");
+        });
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let (address, _) = stand_in(routes);
+        let client = Client::new().with_max_body_size(12);
+        block_on(async {
+            // Exactly at the limit is fine.
+            let chunked = client.get(&format!("{address}/chunked")).await.unwrap();
+            assert_eq!(chunked.text(), "hello, world");
+
+            let small = Client::new().with_max_body_size(11);
+            for path in ["/chunked", "/until-close", "/huge", "/huge-chunk"] {
+                let result = small.get(&format!("{address}{path}")).await;
+                assert!(
+                    matches!(result, Err(Error::BodyTooLarge(11))),
+                    "{path}: {result:?}"
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_redirects() {
+        let (address, _) = stand_in(routes);
+        let client = Client::new().with_max_redirects(3);
+        block_on(async {
+            let moved = client.get(&format!("{address}/old")).await.unwrap();
+            assert_eq!(moved.text(), "hello");
+            assert_eq!(moved.url, format!("{address}/hello"));
+
+            let relative = client
+                .get(&format!("{address}/relative/old"))
+                .await
+                .unwrap();
+            assert_eq!(relative.text(), "new");
+
+            let looping = client.get(&format!("{address}/loop")).await;
+            assert!(matches!(looping, Err(Error::TooManyRedirects)));
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (address, _) = stand_in(routes);
+        let client = Client::new().with_timeout(Duration::from_millis(50));
+        let result = block_on(client.get(&format!("{address}/slow")));
+        assert!(matches!(result, Err(Error::TimedOut)));
+    }
+}
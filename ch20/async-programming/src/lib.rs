@@ -0,0 +1,19 @@
+//! The primitive futures and executor from `main.rs`, grown into something
+//! usable:
+//!
+//! - `blocking`: `spawn_blocking` on a bounded, reusable thread pool
+//! - `credentials`: password hashing on a pool sized to the CPUs, and a
+//!   file-backed user store
+//! - `executor`: an executor that runs many tasks on one thread, and `block_on`
+//! - `fanout`: one operation over many inputs, a few at a time, with retries
+//! - `http`: an HTTP/1.1 client with keep-alive, redirects and timeouts
+//! - `semaphore`: an async semaphore, for limiting concurrency
+//! - `timer`: `sleep`, `timeout` and `interval`, driven by one background thread
+
+pub mod blocking;
+pub mod credentials;
+pub mod executor;
+pub mod fanout;
+pub mod http;
+pub mod semaphore;
+pub mod timer;
@@ -96,6 +96,8 @@ fn main() {
             }
             // cheapo_request2はpollされるたびにTcpStream::conect, socket.write_all, read_to_stringのawait式が返すポーリングを返す。
             // 再度ポーリングされた際はその途中から継続される
+            // レスポンスを解析し、keep-alive、chunked、リダイレクト、タイムアウトに対応した
+            // HTTPクライアントはライブラリのhttp.rsにある
 
             // 非同期関数を同期コードから呼び出す: block_on
             fn fake_main() -> std::io::Result<()> {
@@ -244,6 +246,7 @@ fn main() {
             }
 
             // パスワードの検証
+            // CPU数に合わせたスレッドプールで動く版はcredentials.rsにある
             async fn verify_password(
                 password: &str,
                 hash: &str,
@@ -286,6 +289,9 @@ fn main() {
                 results
             }
 
+            // URLの数だけ一度にタスクを起動してしまうので、同時実行数を制限してリトライもする
+            // 版はライブラリのfanout.rsにある
+
             fn run_requests() {
                 let requests = &["http::example.com".to_string()];
 
@@ -416,6 +422,9 @@ fn main() {
                 }
             }
         }
+        // このspawn_blockingは呼ぶたびにスレッドを作り、block_onは1つのFutureしか動かせない
+        // スレッドプールを使うspawn_blockingと複数タスクのエグゼキュータはライブラリの
+        // blocking.rs, executor.rsにある
     }
 
     // ピン留め
@@ -0,0 +1,207 @@
+//! An async semaphore, for limiting how many tasks do something at once.
+//!
+//! Waiters queue up first come, first served. A freed permit wakes only the
+//! task at the front of the queue, so a thousand waiters don't all get polled
+//! every time one permit comes back.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    /// Tasks waiting for a permit, longest-waiting first.
+    waiters: VecDeque<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl State {
+    fn wake_front(&self) {
+        if let Some((_, waker)) = self.waiters.front() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Wait for a permit. It's returned to the semaphore when dropped.
+    pub fn acquire(self: &Arc<Self>) -> Acquire {
+        Acquire {
+            semaphore: self.clone(),
+            id: None,
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().permits
+    }
+}
+
+pub struct Acquire {
+    semaphore: Arc<Semaphore>,
+    /// Our place in the queue, once we've had to wait.
+    id: Option<u64>,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        let this = &mut *self;
+        let mut state = this.semaphore.state.lock().unwrap();
+
+        let our_turn = match this.id {
+            None => state.waiters.is_empty(),
+            Some(id) => state.waiters.front().map(|(first, _)| *first) == Some(id),
+        };
+        if our_turn && state.permits > 0 {
+            state.permits -= 1;
+            if this.id.take().is_some() {
+                state.waiters.pop_front();
+            }
+            // If more permits are free, the next in line can have one too.
+            if state.permits > 0 {
+                state.wake_front();
+            }
+            return Poll::Ready(Permit {
+                semaphore: this.semaphore.clone(),
+            });
+        }
+
+        match this.id {
+            Some(id) => {
+                if let Some((_, waker)) = state.waiters.iter_mut().find(|(other, _)| *other == id) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back((id, cx.waker().clone()));
+                this.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire {
+    /// Leave the queue. If we were at the front, we may have been woken for
+    /// a permit we'll never take, so pass the wakeup on.
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.semaphore.state.lock().unwrap();
+            let was_front = state.waiters.front().map(|(first, _)| *first) == Some(id);
+            state.waiters.retain(|(other, _)| *other != id);
+            if was_front && state.permits > 0 {
+                state.wake_front();
+            }
+        }
+    }
+}
+
+pub struct Permit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.semaphore.state.lock().unwrap();
+        state.permits += 1;
+        state.wake_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::{block_on, Executor},
+        timer,
+    };
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    use waker_fn::waker_fn;
+
+    #[test]
+    fn test_first_come_first_served() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let executor = Executor::new();
+        let order = Arc::new(Mutex::new(vec![]));
+
+        let held = block_on(semaphore.acquire());
+        let handles: Vec<_> = (0..5)
+            .map(|i| {
+                let semaphore = semaphore.clone();
+                let order = order.clone();
+                executor.spawn(async move {
+                    let _permit = semaphore.acquire().await;
+                    order.lock().unwrap().push(i);
+                })
+            })
+            .collect();
+
+        // Let every task join the queue before the permit comes back.
+        executor.block_on(timer::sleep(Duration::from_millis(10)));
+        assert!(order.lock().unwrap().is_empty());
+        drop(held);
+
+        executor.block_on(async {
+            for handle in handles {
+                handle.await;
+            }
+        });
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_abandoned_wait() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let held = block_on(semaphore.acquire());
+
+        let woken = Arc::new(AtomicBool::new(false));
+        let waker = waker_fn({
+            let woken = woken.clone();
+            move || woken.store(true, Ordering::SeqCst)
+        });
+        let mut context = Context::from_waker(&waker);
+
+        let mut abandoned = semaphore.acquire();
+        let mut behind = semaphore.acquire();
+        assert!(Pin::new(&mut abandoned).poll(&mut context).is_pending());
+        assert!(Pin::new(&mut behind).poll(&mut context).is_pending());
+        woken.store(false, Ordering::SeqCst);
+
+        // The freed permit wakes the front of the queue, which gives up...
+        drop(held);
+        drop(abandoned);
+
+        // ...but passes the wakeup on, so the permit isn't lost.
+        assert!(woken.load(Ordering::SeqCst));
+        let permit = Pin::new(&mut behind).poll(&mut context);
+        assert!(permit.is_ready());
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+}
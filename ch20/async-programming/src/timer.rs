@@ -0,0 +1,340 @@
+//! Timers for the executor: `sleep`, `timeout` and `interval`.
+//!
+//! One background thread keeps a heap of deadlines and sleeps until the
+//! earliest. When a deadline passes it marks the timer as fired and calls its
+//! waker, just as `SpawnBlocking`'s thread does once the closure returns.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, OnceLock, Weak},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures_lite::{FutureExt, Stream};
+
+/// The deadlines the timer thread is waiting for, earliest first.
+struct Timers {
+    queue: Mutex<Queue>,
+    /// Signalled when a new deadline becomes the earliest.
+    changed: Condvar,
+}
+
+struct Queue {
+    heap: BinaryHeap<Reverse<Entry>>,
+    next_seq: u64,
+}
+
+struct Entry {
+    deadline: Instant,
+    /// Breaks ties between equal deadlines, so entries fire in the order
+    /// they were registered.
+    seq: u64,
+    /// Weak, so a `Sleep` dropped early needn't be dug out of the heap.
+    shared: Weak<Mutex<Shared>>,
+}
+
+struct Shared {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+impl Entry {
+    fn key(&self) -> (Instant, u64) {
+        (self.deadline, self.seq)
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// The timers, starting their thread the first time they're needed.
+fn timers() -> &'static Timers {
+    static TIMERS: OnceLock<Arc<Timers>> = OnceLock::new();
+    TIMERS.get_or_init(|| {
+        let timers = Arc::new(Timers {
+            queue: Mutex::new(Queue {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+            changed: Condvar::new(),
+        });
+        let driver = timers.clone();
+        thread::Builder::new()
+            .name("timer".to_string())
+            .spawn(move || driver.run())
+            .expect("failed to start the timer thread");
+        timers
+    })
+}
+
+impl Timers {
+    fn register(&self, deadline: Instant, shared: &Arc<Mutex<Shared>>) {
+        let mut queue = self.queue.lock().unwrap();
+        let entry = Entry {
+            deadline,
+            seq: queue.next_seq,
+            shared: Arc::downgrade(shared),
+        };
+        queue.next_seq += 1;
+
+        let earliest = queue
+            .heap
+            .peek()
+            .is_none_or(|Reverse(first)| entry < *first);
+        queue.heap.push(Reverse(entry));
+        if earliest {
+            self.changed.notify_one();
+        }
+    }
+
+    fn run(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut due = vec![];
+            while queue
+                .heap
+                .peek()
+                .is_some_and(|Reverse(first)| first.deadline <= now)
+            {
+                let Reverse(entry) = queue.heap.pop().unwrap();
+                if let Some(shared) = entry.shared.upgrade() {
+                    due.push(shared);
+                }
+            }
+
+            if !due.is_empty() {
+                // Wake outside the queue's lock, since a woken task may well
+                // register its next timer straight away.
+                drop(queue);
+                for shared in due {
+                    let maybe_waker = {
+                        let mut guard = shared.lock().unwrap();
+                        guard.fired = true;
+                        guard.waker.take()
+                    };
+                    if let Some(waker) = maybe_waker {
+                        waker.wake();
+                    }
+                }
+                queue = self.queue.lock().unwrap();
+                continue;
+            }
+
+            queue = match queue.heap.peek() {
+                Some(Reverse(first)) => {
+                    let wait = first.deadline - now;
+                    self.changed.wait_timeout(queue, wait).unwrap().0
+                }
+                None => self.changed.wait(queue).unwrap(),
+            };
+        }
+    }
+}
+
+/// A future that becomes ready once its deadline has passed.
+pub struct Sleep {
+    deadline: Instant,
+    /// Set up the first time we're polled too early.
+    shared: Option<Arc<Mutex<Shared>>>,
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        shared: None,
+    }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let deadline = self.deadline;
+        let shared = self.shared.get_or_insert_with(|| {
+            let shared = Arc::new(Mutex::new(Shared {
+                fired: false,
+                waker: None,
+            }));
+            timers().register(deadline, &shared);
+            shared
+        });
+
+        let mut guard = shared.lock().unwrap();
+        if guard.fired {
+            return Poll::Ready(());
+        }
+        guard.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// The error `timeout` returns when the future didn't finish in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("timed out")
+    }
+}
+
+impl Error for TimedOut {}
+
+/// Run `future`, giving up on it if it takes longer than `duration`.
+pub async fn timeout<F: Future>(future: F, duration: Duration) -> Result<F::Output, TimedOut> {
+    // `or` polls the left side first, so a future that finishes just as time
+    // runs out still counts as finished.
+    async { Ok(future.await) }
+        .or(async {
+            sleep(duration).await;
+            Err(TimedOut)
+        })
+        .await
+}
+
+/// A stream yielding the time of each tick, one `period` apart.
+pub struct Interval {
+    sleep: Sleep,
+    period: Duration,
+}
+
+/// Tick every `period`, starting one period from now. Ticks missed because
+/// the stream wasn't polled in time are skipped rather than fired in a burst.
+pub fn interval(period: Duration) -> Interval {
+    assert!(
+        period > Duration::ZERO,
+        "an interval's period must be positive"
+    );
+    Interval {
+        sleep: sleep(period),
+        period,
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let tick = self.sleep.deadline;
+        let mut next = tick + self.period;
+        let now = Instant::now();
+        if next <= now {
+            next = now + self.period;
+        }
+        self.sleep = sleep_until(next);
+        Poll::Ready(Some(tick))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{block_on, Executor};
+    use futures_lite::StreamExt;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn test_sleep() {
+        let start = Instant::now();
+        block_on(sleep(30 * MS));
+        assert!(start.elapsed() >= 30 * MS);
+
+        // A deadline already past is ready at once.
+        block_on(sleep(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_sleeps_wake_in_deadline_order() {
+        let executor = Executor::new();
+        let finished = Arc::new(Mutex::new(vec![]));
+        let start = Instant::now();
+
+        let handles: Vec<_> = [50, 10, 40, 20, 30]
+            .into_iter()
+            .map(|ms| {
+                let finished = finished.clone();
+                executor.spawn(async move {
+                    sleep(ms * MS).await;
+                    finished.lock().unwrap().push(ms);
+                })
+            })
+            .collect();
+        // Dropping a sleep before it fires is harmless.
+        drop(sleep(5 * MS));
+
+        executor.block_on(async {
+            for handle in handles {
+                handle.await;
+            }
+        });
+        assert_eq!(*finished.lock().unwrap(), vec![10, 20, 30, 40, 50]);
+        // The sleeps overlapped rather than running one after another.
+        assert!(start.elapsed() < 140 * MS);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let quick = block_on(timeout(async { 7 }, 50 * MS));
+        assert_eq!(quick, Ok(7));
+
+        let start = Instant::now();
+        let slow = block_on(timeout(sleep(Duration::from_secs(10)), 20 * MS));
+        assert_eq!(slow, Err(TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_interval() {
+        let start = Instant::now();
+        let ticks: Vec<Instant> = block_on(interval(20 * MS).take(3).collect());
+        assert_eq!(ticks.len(), 3);
+        assert!(ticks[0] >= start + 20 * MS);
+        for pair in ticks.windows(2) {
+            assert!(pair[1] - pair[0] >= 20 * MS);
+        }
+    }
+}